@@ -0,0 +1,154 @@
+// Requests served without payment
+// Free routes ( health checks, docs, preflights ) skip the middleware entirely. Internal services on the allowlist
+// sign an internal token instead of a voucher, they aren't charged but are still rate limited and logged
+
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    dyn_abi::DynSolValue,
+    primitives::{keccak256, Address, U256},
+    signers::{Signature, SignerSync},
+};
+use axum::http::Method;
+
+use crate::{audit::Charge, channel::ChannelState, config::Settings, error::AuthError};
+
+// Signature of the internal token, sent along with `X-Timestamp`
+pub const INTERNAL_HEADER: &str = "X-Internal-Signature";
+
+#[derive(Clone, Debug, Default)]
+pub struct AccessPolicy {
+    free_paths: HashSet<String>, // Exact paths, or prefixes ending with `*`
+    free_methods: HashSet<Method>,
+    allowlist: HashSet<Address>, // Senders allowed to use internal tokens
+}
+
+impl AccessPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_free_path(mut self, path: impl Into<String>) -> Self {
+        self.free_paths.insert(path.into());
+        self
+    }
+
+    pub fn with_free_method(mut self, method: Method) -> Self {
+        self.free_methods.insert(method);
+        self
+    }
+
+    pub fn with_allowed_sender(mut self, sender: Address) -> Self {
+        self.allowlist.insert(sender);
+        self
+    }
+
+    pub fn is_free(&self, method: &Method, path: &str) -> bool {
+        self.free_methods.contains(method)
+            || self.free_paths.iter().any(|free| matches_path(free, path))
+    }
+
+    pub fn free_paths(&self) -> &HashSet<String> {
+        &self.free_paths
+    }
+
+    pub fn free_methods(&self) -> &HashSet<Method> {
+        &self.free_methods
+    }
+
+    pub fn allowed_senders(&self) -> &HashSet<Address> {
+        &self.allowlist
+    }
+
+    pub fn is_allowed(&self, sender: Address) -> bool {
+        self.allowlist.contains(&sender)
+    }
+}
+
+// An exact path, or a prefix ending with `*`
+pub(crate) fn matches_path(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+// What an internal service signs for a request, bound to the endpoint and fresh
+pub fn create_internal_message(method: &Method, path: &str, timestamp: u64) -> Vec<u8> {
+    let message = DynSolValue::Tuple(vec![
+        DynSolValue::String("PipeGate internal".to_string()),
+        DynSolValue::String(method.to_string()),
+        DynSolValue::String(path.to_string()),
+        DynSolValue::Uint(U256::from(timestamp), 256),
+    ]);
+
+    keccak256(message.abi_encode_packed()).to_vec()
+}
+
+// Client side, the token to send in `INTERNAL_HEADER`
+pub fn sign_internal_token<S: SignerSync>(
+    signer: &S,
+    method: &Method,
+    path: &str,
+    timestamp: u64,
+) -> Result<Signature, AuthError> {
+    let message = create_internal_message(method, path, timestamp);
+    let signature = signer
+        .sign_message_sync(&message)
+        .map_err(|_| AuthError::InvalidSignature)?;
+
+    Signature::try_from(signature.as_bytes().as_slice()).map_err(|_| AuthError::InvalidSignature)
+}
+
+// Check the internal token of a request, returns the internal sender it was signed by
+// `settings` are the ones the request started with, see `ChannelState::settings`
+pub async fn verify_internal_request(
+    state: &ChannelState,
+    settings: &Settings,
+    method: &Method,
+    path: &str,
+    timestamp: u64,
+    signature: &Signature,
+) -> Result<Address, AuthError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    // Fresh within the same window as the payments, a token stamped ahead could be replayed until then otherwise
+    if timestamp.abs_diff(now) > state.timestamp_window() {
+        return Err(AuthError::Expired);
+    }
+
+    let message = create_internal_message(method, path, timestamp);
+    let sender = signature
+        .recover_address_from_msg(message)
+        .map_err(|_| AuthError::InvalidSignature)?;
+
+    if !settings.access.is_allowed(sender) || state.is_blocked(sender) {
+        println!("Failed: Sender {} not allowed", sender);
+        return Err(AuthError::SenderNotAllowed);
+    }
+
+    // Not charged, but not unlimited either
+    state.check_rate_limit(sender, settings).await?;
+
+    // Recorded along with the charges, for nothing from no channel
+    if let Some(audit_log) = state.audit_log() {
+        let bypass = Charge {
+            channel_id: U256::ZERO,
+            sender,
+            nonce: U256::ZERO,
+            amount: U256::ZERO,
+            route: Some(format!("{} {}", method, path)),
+            signature: *signature,
+        };
+        if let Err(e) = audit_log.append(bypass) {
+            println!("Failed: Audit log write - Error {}", e);
+        }
+    }
+
+    Ok(sender)
+}
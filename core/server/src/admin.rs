@@ -0,0 +1,306 @@
+// Admin API, for the operators to see what the server holds and step in by hand
+// Lists the channels and the history of a sender, settles a channel with its best voucher, blocks senders and
+// corrects channels. Every call needs the admin token as a bearer token, mount it apart from the paid routes
+
+use std::path::PathBuf;
+
+use alloy::{
+    primitives::{keccak256, Address, FixedBytes, U256},
+    signers::local::PrivateKeySigner,
+};
+use axum::{
+    extract::{Path, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    audit::AuditEntry,
+    channel::ChannelState,
+    config::Pipegate,
+    types::{ChannelAdjustment, ChannelRecord, ChannelStatus, ChannelVoucher},
+};
+
+// What the audit log records as the source of the changes made through the API
+const ADMIN_SOURCE: &str = "admin API";
+
+#[derive(Clone)]
+pub struct Admin {
+    state: ChannelState,
+    token_hash: FixedBytes<32>, // Only the hash is kept around
+    settlement_key: Option<PrivateKeySigner>, // Closes the channels, the server signer if not set
+    reload: Option<(Pipegate, PathBuf)>, // Config file the settings are reloaded from
+}
+
+// What the admin API shows of a channel
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelSummary {
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: U256,
+    pub address: Address,
+    pub sender: Address,
+    pub recipient: Address,
+
+    // Left to the sender
+    #[serde_as(as = "DisplayFromStr")]
+    pub balance: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub nonce: U256,
+
+    // Charged so far, what settling the channel pays out
+    #[serde_as(as = "DisplayFromStr")]
+    pub accrued: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub deposited: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub expiration: U256,
+
+    pub status: ChannelStatus,
+    pub updated_at: u64,
+    pub settleable: bool, // A voucher is held to close the channel with
+}
+
+impl From<&ChannelRecord> for ChannelSummary {
+    fn from(record: &ChannelRecord) -> Self {
+        Self {
+            channel_id: record.channel.channel_id,
+            address: record.channel.address,
+            sender: record.channel.sender,
+            recipient: record.channel.recipient,
+            balance: record.deposited.saturating_sub(record.served),
+            nonce: record.channel.nonce,
+            accrued: record.served,
+            deposited: record.deposited,
+            expiration: record.channel.expiration,
+            status: record.status,
+            updated_at: record.updated_at,
+            settleable: record.voucher.is_some(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SenderHistory {
+    pub sender: Address,
+    pub blocked: bool,
+    pub channels: Vec<ChannelSummary>,
+    pub charges: Vec<AuditEntry>, // From the audit log, empty without one
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Settlement {
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: U256,
+    pub tx_hash: FixedBytes<32>,
+}
+
+impl Admin {
+    pub fn new(state: ChannelState, token: &str) -> Self {
+        Self {
+            state,
+            token_hash: keccak256(token),
+            settlement_key: None,
+            reload: None,
+        }
+    }
+
+    // Settle with this key rather than the server signer
+    pub fn with_settlement_key(mut self, signer: PrivateKeySigner) -> Self {
+        self.settlement_key = Some(signer);
+        self
+    }
+
+    // Enable `POST /reload`, reloading the settings of `pipegate` from `path`
+    pub fn with_reload(mut self, pipegate: Pipegate, path: impl Into<PathBuf>) -> Self {
+        self.reload = Some((pipegate, path.into()));
+        self
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/channels", get(list_channels))
+            .route("/channels/:channel_id", get(show_channel))
+            .route("/channels/:channel_id/voucher", get(show_voucher))
+            .route("/channels/:channel_id/settle", post(settle))
+            .route("/channels/:channel_id/adjust", post(adjust))
+            .route("/senders/:sender", get(sender_history))
+            .route("/senders/:sender/block", post(block).delete(unblock))
+            .route("/reload", post(reload))
+            .route_layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                authenticate,
+            ))
+            .with_state(self)
+    }
+}
+
+async fn authenticate(
+    State(admin): State<Admin>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    // Hashes are compared so the time taken says nothing about the token
+    match token {
+        Some(token) if keccak256(token) == admin.token_hash => Ok(next.run(request).await),
+        _ => {
+            println!("Failed: Admin request not authenticated");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+async fn list_channels(State(admin): State<Admin>) -> Json<Vec<ChannelSummary>> {
+    let records = admin.state.records().await;
+    Json(records.iter().map(ChannelSummary::from).collect())
+}
+
+async fn show_channel(
+    State(admin): State<Admin>,
+    Path(channel_id): Path<U256>,
+) -> Result<Json<ChannelSummary>, StatusCode> {
+    let record = admin
+        .state
+        .get_record(channel_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(ChannelSummary::from(&record)))
+}
+
+// To settle with the command line instead, see `cli`
+async fn show_voucher(
+    State(admin): State<Admin>,
+    Path(channel_id): Path<U256>,
+) -> Result<Json<ChannelVoucher>, StatusCode> {
+    let record = admin
+        .state
+        .get_record(channel_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    record.voucher.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn sender_history(
+    State(admin): State<Admin>,
+    Path(sender): Path<Address>,
+) -> Json<SenderHistory> {
+    let channels = admin
+        .state
+        .records()
+        .await
+        .iter()
+        .filter(|record| record.channel.sender == sender)
+        .map(ChannelSummary::from)
+        .collect();
+    let charges = admin
+        .state
+        .audit_log()
+        .map(|audit_log| {
+            audit_log
+                .entries()
+                .into_iter()
+                .filter(|entry| entry.change.is_none() && entry.sender == sender)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Json(SenderHistory {
+        sender,
+        blocked: admin.state.is_blocked(sender),
+        channels,
+        charges,
+    })
+}
+
+// Close the channel onchain with the voucher claiming the lowest balance
+async fn settle(
+    State(admin): State<Admin>,
+    Path(channel_id): Path<U256>,
+) -> Result<Json<Settlement>, StatusCode> {
+    let record = admin
+        .state
+        .get_record(channel_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let voucher = record.voucher.ok_or_else(|| {
+        println!("Failed: No voucher to settle channel {} with", channel_id);
+        StatusCode::CONFLICT
+    })?;
+    let signer = match admin.settlement_key.as_ref().or(admin.state.signer()) {
+        Some(signer) => signer.clone(),
+        None => {
+            println!("Failed: No key to settle with");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+    };
+
+    let tx_hash = admin
+        .state
+        .settle_channel(
+            &signer,
+            &voucher.payment_channel,
+            &voucher.signature,
+            voucher.raw_body,
+        )
+        .await?;
+
+    Ok(Json(Settlement {
+        channel_id,
+        tx_hash,
+    }))
+}
+
+async fn adjust(
+    State(admin): State<Admin>,
+    Path(channel_id): Path<U256>,
+    Json(adjustment): Json<ChannelAdjustment>,
+) -> Result<Json<ChannelSummary>, StatusCode> {
+    // Only the middleware validates channels, one left pending would hold its requests until it times out
+    if adjustment.status == Some(ChannelStatus::PendingValidation) {
+        println!(
+            "Failed: Channel {} can't be set pending validation",
+            channel_id
+        );
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let record = admin
+        .state
+        .adjust_channel(channel_id, &adjustment, ADMIN_SOURCE)
+        .await?;
+    Ok(Json(ChannelSummary::from(&record)))
+}
+
+async fn block(State(admin): State<Admin>, Path(sender): Path<Address>) -> StatusCode {
+    admin.state.block_sender(sender, ADMIN_SOURCE);
+    StatusCode::NO_CONTENT
+}
+
+async fn unblock(State(admin): State<Admin>, Path(sender): Path<Address>) -> StatusCode {
+    admin.state.unblock_sender(sender, ADMIN_SOURCE);
+    StatusCode::NO_CONTENT
+}
+
+async fn reload(State(admin): State<Admin>) -> Result<Json<String>, StatusCode> {
+    let (pipegate, path) = admin.reload.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let settings = pipegate.reload(path, ADMIN_SOURCE).map_err(|e| {
+        println!("Failed: Reload {} - Error {}", path.display(), e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+    Ok(Json(settings.summary()))
+}
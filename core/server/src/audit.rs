@@ -0,0 +1,282 @@
+// Append-only audit log of the charges, and of the changes to the server settings
+// Every entry commits to the hash of the previous one, so edits, removals and reordering are detected by `verify_entries`
+// The log is kept in memory and, if opened on a file, appended to it as JSON Lines
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    dyn_abi::DynSolValue,
+    hex,
+    primitives::{keccak256, Address, FixedBytes, Parity, U256},
+    signers::Signature,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+// A charge, as committed by `verify_and_update_channel`
+#[derive(Clone, Debug)]
+pub struct Charge {
+    pub channel_id: U256,
+    pub sender: Address,
+    pub nonce: U256,
+    pub amount: U256,
+    pub route: Option<String>, // `METHOD /path`, when known
+    pub signature: Signature,  // Voucher paying for the request
+}
+
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub index: u64,
+    pub timestamp: u64,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: U256,
+
+    pub sender: Address,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub nonce: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: U256,
+
+    pub route: String,
+    pub signature: Signature,
+    pub previous_hash: FixedBytes<32>, // Zero for the first entry
+    pub hash: FixedBytes<32>,          // Over all the other fields
+
+    // Set on the entries recording a change to the server rather than a charge, the charge fields are zero then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn compute_hash(&self) -> FixedBytes<32> {
+        let encoded = DynSolValue::Tuple(vec![
+            DynSolValue::Uint(U256::from(self.index), 64),
+            DynSolValue::Uint(U256::from(self.timestamp), 64),
+            DynSolValue::Uint(self.channel_id, 256),
+            DynSolValue::Address(self.sender),
+            DynSolValue::Uint(self.nonce, 256),
+            DynSolValue::Uint(self.amount, 256),
+            DynSolValue::FixedBytes(keccak256(self.route.as_bytes()), 32),
+            DynSolValue::Bytes(self.signature.as_bytes().to_vec()),
+            DynSolValue::FixedBytes(self.previous_hash, 32),
+        ]);
+
+        // Charges hash as they did before changes were recorded
+        let mut encoded = encoded.abi_encode_packed();
+        if let Some(change) = &self.change {
+            encoded.extend_from_slice(keccak256(change.as_bytes()).as_slice());
+        }
+
+        keccak256(encoded)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditViolation {
+    Gap { expected: u64, found: u64 }, // Entries missing before `found`
+    BrokenChain { index: u64 },        // Doesn't point to the previous entry
+    Edited { index: u64 },             // Content doesn't match its hash
+}
+
+// Check the entries form an unbroken chain, from the first entry of the log on
+// A log missing its head doesn't verify, however well the rest is chained
+pub fn verify_entries(entries: &[AuditEntry]) -> Result<(), AuditViolation> {
+    let mut previous: Option<&AuditEntry> = None;
+
+    for entry in entries {
+        if let Some(previous) = previous {
+            if entry.index != previous.index + 1 {
+                return Err(AuditViolation::Gap {
+                    expected: previous.index + 1,
+                    found: entry.index,
+                });
+            }
+            if entry.previous_hash != previous.hash {
+                return Err(AuditViolation::BrokenChain { index: entry.index });
+            }
+        } else if entry.index != 0 {
+            return Err(AuditViolation::Gap {
+                expected: 0,
+                found: entry.index,
+            });
+        } else if entry.previous_hash != FixedBytes::ZERO {
+            return Err(AuditViolation::BrokenChain { index: 0 });
+        }
+
+        if entry.compute_hash() != entry.hash {
+            return Err(AuditViolation::Edited { index: entry.index });
+        }
+        previous = Some(entry);
+    }
+
+    Ok(())
+}
+
+// Read entries exported as JSON Lines
+pub fn read_jsonl(reader: impl io::Read) -> io::Result<Vec<AuditEntry>> {
+    BufReader::new(reader)
+        .lines()
+        .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|line| {
+            serde_json::from_str(&line?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog {
+    entries: Arc<Mutex<Vec<AuditEntry>>>,
+    path: Option<PathBuf>, // File the entries are appended to
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Resume the log from the file, the entries appended from now on are written to it
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match File::open(&path) {
+            Ok(file) => read_jsonl(file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        if let Err(violation) = verify_entries(&entries) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Audit log {} corrupted: {:?}", path.display(), violation),
+            ));
+        }
+
+        Ok(Self {
+            entries: Arc::new(Mutex::new(entries)),
+            path: Some(path),
+        })
+    }
+
+    pub fn append(&self, charge: Charge) -> io::Result<AuditEntry> {
+        self.push(
+            charge.channel_id,
+            charge.sender,
+            charge.nonce,
+            charge.amount,
+            charge.route.unwrap_or_default(),
+            charge.signature,
+            None,
+        )
+    }
+
+    // Record a change to the server, e.g. reloaded settings, in the same chain as the charges
+    pub fn record_change(&self, change: impl Into<String>) -> io::Result<AuditEntry> {
+        self.push(
+            U256::ZERO,
+            Address::ZERO,
+            U256::ZERO,
+            U256::ZERO,
+            String::new(),
+            Signature::new(U256::ZERO, U256::ZERO, Parity::Parity(false)),
+            Some(change.into()),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push(
+        &self,
+        channel_id: U256,
+        sender: Address,
+        nonce: U256,
+        amount: U256,
+        route: String,
+        signature: Signature,
+        change: Option<String>,
+    ) -> io::Result<AuditEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let (index, previous_hash) = match entries.last() {
+            Some(last) => (last.index + 1, last.hash),
+            None => (0, FixedBytes::ZERO),
+        };
+
+        let mut entry = AuditEntry {
+            index,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            channel_id,
+            sender,
+            nonce,
+            amount,
+            route,
+            signature,
+            previous_hash,
+            hash: FixedBytes::ZERO,
+            change,
+        };
+        entry.hash = entry.compute_hash();
+
+        // Written before it's kept, so that the file never misses an entry held in memory
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&entry).unwrap())?;
+        }
+
+        entries.push(entry.clone());
+        Ok(entry)
+    }
+
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    pub fn verify(&self) -> Result<(), AuditViolation> {
+        verify_entries(&self.entries.lock().unwrap())
+    }
+
+    pub fn export_jsonl(&self, mut writer: impl Write) -> io::Result<()> {
+        for entry in self.entries.lock().unwrap().iter() {
+            writeln!(writer, "{}", serde_json::to_string(entry).unwrap())?;
+        }
+        Ok(())
+    }
+
+    pub fn export_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(
+            writer,
+            "index,timestamp,channel_id,sender,nonce,amount,route,signature,previous_hash,hash,change"
+        )?;
+        for entry in self.entries.lock().unwrap().iter() {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},\"{}\",0x{},{},{},\"{}\"",
+                entry.index,
+                entry.timestamp,
+                entry.channel_id,
+                entry.sender,
+                entry.nonce,
+                entry.amount,
+                entry.route.replace('"', "\"\""),
+                hex::encode(entry.signature.as_bytes()),
+                entry.previous_hash,
+                entry.hash,
+                entry
+                    .change
+                    .as_deref()
+                    .unwrap_or_default()
+                    .replace('"', "\"\""),
+            )?;
+        }
+        Ok(())
+    }
+}
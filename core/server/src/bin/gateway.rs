@@ -0,0 +1,52 @@
+// Standalone payment gateway in front of an upstream HTTP service
+// Usage: gateway [config.toml], the path can also be set with `PIPEGATE_CONFIG`, defaults to `gateway.toml`
+// The environment overrides of `PipegateConfig::with_env` apply
+
+use std::env;
+
+use pipegate::gateway::{gateway_router, GatewayConfig};
+
+#[tokio::main]
+pub async fn main() {
+    let path = env::args()
+        .nth(1)
+        .or_else(|| env::var("PIPEGATE_CONFIG").ok())
+        .unwrap_or_else(|| "gateway.toml".to_string());
+
+    let config = GatewayConfig::from_file(&path).and_then(|mut config| {
+        config.pipegate = config.pipegate.with_env()?;
+        Ok(config)
+    });
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed: Config {} - Error {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let pipegate = match config.pipegate.build() {
+        Ok(pipegate) => pipegate,
+        Err(e) => {
+            eprintln!("Failed: Setup - Error {}", e);
+            std::process::exit(1);
+        }
+    };
+    pipegate.spawn_tasks();
+    #[cfg(unix)]
+    pipegate.spawn_reload_on_sighup(path.clone().into());
+
+    let mut app = gateway_router(&pipegate, config.upstream.clone());
+    // Not forwarded, nor charged
+    if let Some(admin) = pipegate.admin_router(&path) {
+        app = app.nest("/admin", admin);
+    }
+
+    let listen = config.pipegate.server.listen;
+    let listener = tokio::net::TcpListener::bind(listen).await.unwrap();
+    println!(
+        "Gateway listening on: http://{}, forwarding to {}",
+        listen, config.upstream
+    );
+    axum::serve(listener, app).await.unwrap();
+}
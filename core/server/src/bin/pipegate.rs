@@ -0,0 +1,13 @@
+// Command line for providers, see `pipegate::cli`
+// Usage: pipegate [--config pipegate.toml] [--rpc-url URL] [--factory ADDRESS] [--key-env VAR] <register|inspect|settle|doctor>
+
+use clap::Parser;
+use pipegate::cli::{run, Cli};
+
+#[tokio::main]
+pub async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("Failed: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,264 @@
+// In-memory chain for tests, no network involved
+// Channels, factory registrations and logs are set up by the test, closes are recorded for inspection
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use alloy::{
+    primitives::{keccak256, Address, Bytes, FixedBytes, Log as PrimitiveLog, U256},
+    rpc::types::{Filter, Log},
+    signers::{local::PrivateKeySigner, Signature},
+    sol_types::SolEvent,
+};
+use async_trait::async_trait;
+
+use crate::{
+    channel::PaymentChannelContract,
+    error::AuthError,
+    types::{ChannelInfo, PaymentChannel},
+    utils::create_message,
+};
+
+use super::ChainBackend;
+
+// A close submitted to the mock chain
+#[derive(Clone, Debug)]
+pub struct MockClose {
+    pub sender: Address, // Account the close was sent from
+    pub payment_channel: PaymentChannel,
+    pub signature: Signature,
+    pub raw_body: Bytes,
+    pub amount: U256, // Paid out to the recipient
+}
+
+#[derive(Debug, Default)]
+struct MockChain {
+    chain_id: u64,
+    channels: HashMap<Address, ChannelInfo>,
+    factory: HashMap<(Address, U256), Address>,
+    logs: Vec<Log>,
+    block_number: u64,
+    reorgs: HashMap<u64, u64>, // Block number -> times it was replaced
+    closes: Vec<MockClose>,
+    fail_closes: bool,
+    read_delay: Duration, // How long the contract reads take, like a slow RPC
+}
+
+#[derive(Clone, Debug)]
+pub struct MockBackend {
+    chain: Arc<Mutex<MockChain>>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self {
+            chain: Arc::new(Mutex::new(MockChain {
+                chain_id: 31337,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn set_read_delay(&self, delay: Duration) {
+        self.chain.lock().unwrap().read_delay = delay;
+    }
+
+    pub fn set_chain_id(&self, chain_id: u64) {
+        self.chain.lock().unwrap().chain_id = chain_id;
+    }
+
+    // Add or replace a channel contract
+    pub fn set_channel(&self, info: ChannelInfo) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.channels.insert(info.address, info);
+    }
+
+    pub fn channel(&self, address: Address) -> Option<ChannelInfo> {
+        self.chain.lock().unwrap().channels.get(&address).cloned()
+    }
+
+    // Register the channel contract in the factory mapping
+    pub fn register_channel(&self, factory: Address, channel_id: U256, address: Address) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.factory.insert((factory, channel_id), address);
+    }
+
+    pub fn set_block_number(&self, number: u64) {
+        self.chain.lock().unwrap().block_number = number;
+    }
+
+    // Replace the block and the ones after it, and drop the logs they had, as a reorg would
+    pub fn reorg(&self, number: u64) {
+        let mut chain = self.chain.lock().unwrap();
+        for block in number..=chain.block_number.max(number) {
+            *chain.reorgs.entry(block).or_default() += 1;
+        }
+        chain
+            .logs
+            .retain(|log| log.block_number.is_some_and(|block| block < number));
+    }
+
+    // Emit an event from the contract at `address` in the given block
+    pub fn emit<E: SolEvent>(&self, address: Address, event: &E, block_number: u64) {
+        let mut chain = self.chain.lock().unwrap();
+        let log_index = chain.logs.len() as u64;
+        let block_hash = mock_block_hash(&chain, block_number);
+
+        chain.logs.push(Log {
+            inner: PrimitiveLog {
+                address,
+                data: event.encode_log_data(),
+            },
+            block_hash: Some(block_hash),
+            block_number: Some(block_number),
+            log_index: Some(log_index),
+            ..Default::default()
+        });
+        chain.block_number = chain.block_number.max(block_number);
+    }
+
+    // Closes submitted so far
+    pub fn closes(&self) -> Vec<MockClose> {
+        self.chain.lock().unwrap().closes.clone()
+    }
+
+    // Make the following closes fail, as a reverted transaction would
+    pub fn fail_closes(&self, fail: bool) {
+        self.chain.lock().unwrap().fail_closes = fail;
+    }
+}
+
+fn mock_block_hash(chain: &MockChain, number: u64) -> FixedBytes<32> {
+    let version = chain.reorgs.get(&number).copied().unwrap_or_default();
+    keccak256([number.to_be_bytes(), version.to_be_bytes()].concat())
+}
+
+#[async_trait]
+impl ChainBackend for MockBackend {
+    async fn chain_id(&self) -> Result<u64, AuthError> {
+        Ok(self.chain.lock().unwrap().chain_id)
+    }
+
+    async fn channel_info(&self, address: Address) -> Result<ChannelInfo, AuthError> {
+        let delay = self.chain.lock().unwrap().read_delay;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        self.channel(address)
+            .ok_or_else(|| AuthError::ContractError(format!("No contract at {}", address)))
+    }
+
+    async fn factory_channel(
+        &self,
+        factory: Address,
+        channel_id: U256,
+    ) -> Result<Address, AuthError> {
+        let chain = self.chain.lock().unwrap();
+        Ok(chain
+            .factory
+            .get(&(factory, channel_id))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn close_channel(
+        &self,
+        signer: &PrivateKeySigner,
+        payment_channel: &PaymentChannel,
+        signature: &Signature,
+        raw_body: Bytes,
+    ) -> Result<FixedBytes<32>, AuthError> {
+        let mut chain = self.chain.lock().unwrap();
+        if chain.fail_closes {
+            return Err(AuthError::ContractError("execution reverted".to_string()));
+        }
+
+        let info = chain
+            .channels
+            .get_mut(&payment_channel.address)
+            .ok_or_else(|| AuthError::ContractError("execution reverted".to_string()))?;
+
+        // As the contract does, the voucher has to be signed by the sender of the channel
+        let message = create_message(
+            info.channel_id,
+            payment_channel.balance,
+            payment_channel.nonce,
+            &raw_body,
+        );
+        if !signature
+            .recover_address_from_msg(message)
+            .is_ok_and(|address| address == info.sender)
+        {
+            return Err(AuthError::ContractError("execution reverted".to_string()));
+        }
+
+        // The contract pays out the whole balance, to the recipient and back to the sender
+        let amount = info.balance.saturating_sub(payment_channel.balance);
+        info.balance = U256::ZERO;
+
+        let event = PaymentChannelContract::channelClosed {
+            channel_id: info.channel_id,
+            sender: info.sender,
+            recipient: info.recipient,
+            timestamp: U256::ZERO,
+            amount,
+            nonce: payment_channel.nonce,
+        };
+
+        chain.closes.push(MockClose {
+            sender: signer.address(),
+            payment_channel: payment_channel.clone(),
+            signature: *signature,
+            raw_body,
+            amount,
+        });
+        let block_number = chain.block_number + 1;
+        drop(chain);
+
+        self.emit(payment_channel.address, &event, block_number);
+
+        Ok(keccak256(signature.as_bytes()))
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, AuthError> {
+        let chain = self.chain.lock().unwrap();
+        let from = filter.get_from_block().unwrap_or_default();
+        let to = filter.get_to_block().unwrap_or(u64::MAX);
+
+        Ok(chain
+            .logs
+            .iter()
+            .filter(|log| {
+                let block = log.block_number.unwrap_or_default();
+                let topics = log.topics();
+
+                block >= from
+                    && block <= to
+                    && filter.address.matches(&log.address())
+                    && filter.topics.iter().enumerate().all(|(i, topic)| {
+                        topic.is_empty() || topics.get(i).is_some_and(|t| topic.matches(t))
+                    })
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn block_number(&self) -> Result<u64, AuthError> {
+        Ok(self.chain.lock().unwrap().block_number)
+    }
+
+    async fn block_hash(&self, number: u64) -> Result<FixedBytes<32>, AuthError> {
+        let chain = self.chain.lock().unwrap();
+        Ok(mock_block_hash(&chain, number))
+    }
+}
@@ -0,0 +1,52 @@
+// Chain access for the middleware
+// Everything the server needs from the chain goes through `ChainBackend`, so that it can be swapped for the in-memory mock in tests
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod mock;
+pub mod provider;
+
+use alloy::{
+    primitives::{Address, Bytes, FixedBytes, U256},
+    rpc::types::{Filter, Log},
+    signers::{local::PrivateKeySigner, Signature},
+};
+use async_trait::async_trait;
+
+use crate::{
+    error::AuthError,
+    types::{ChannelInfo, PaymentChannel},
+};
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use mock::MockBackend;
+pub use provider::AlloyBackend;
+
+#[async_trait]
+pub trait ChainBackend: Send + Sync {
+    async fn chain_id(&self) -> Result<u64, AuthError>;
+
+    // Read the current state of a channel contract
+    async fn channel_info(&self, address: Address) -> Result<ChannelInfo, AuthError>;
+
+    // Read the channel contract registered in the factory for the channel id
+    async fn factory_channel(
+        &self,
+        factory: Address,
+        channel_id: U256,
+    ) -> Result<Address, AuthError>;
+
+    // Close the channel with the sender's signed voucher, returns the transaction hash once it's mined
+    async fn close_channel(
+        &self,
+        signer: &PrivateKeySigner,
+        payment_channel: &PaymentChannel,
+        signature: &Signature,
+        raw_body: Bytes,
+    ) -> Result<FixedBytes<32>, AuthError>;
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, AuthError>;
+
+    async fn block_number(&self) -> Result<u64, AuthError>;
+
+    async fn block_hash(&self, number: u64) -> Result<FixedBytes<32>, AuthError>;
+}
@@ -0,0 +1,164 @@
+use alloy::{
+    eips::BlockNumberOrTag,
+    primitives::{Address, Bytes, FixedBytes, U256},
+    providers::{Provider, ProviderBuilder},
+    rpc::types::{BlockTransactionsKind, Filter, Log},
+    signers::{local::PrivateKeySigner, Signature},
+    transports::http::reqwest::Url,
+};
+use async_trait::async_trait;
+
+use crate::{
+    channel::{close_channel, ChannelFactoryContract, PaymentChannelContract},
+    error::AuthError,
+    types::{ChannelInfo, PaymentChannel},
+};
+
+use super::ChainBackend;
+
+// Chain access over JSON-RPC with alloy, the default backend
+#[derive(Clone, Debug)]
+pub struct AlloyBackend {
+    rpc_url: Url,
+}
+
+impl AlloyBackend {
+    pub fn new(rpc_url: Url) -> Self {
+        Self { rpc_url }
+    }
+
+    pub fn rpc_url(&self) -> &Url {
+        &self.rpc_url
+    }
+}
+
+#[async_trait]
+impl ChainBackend for AlloyBackend {
+    async fn chain_id(&self) -> Result<u64, AuthError> {
+        let provider = ProviderBuilder::new().on_http(self.rpc_url.clone());
+
+        provider
+            .get_chain_id()
+            .await
+            .map_err(|e| AuthError::NetworkError(e.to_string()))
+    }
+
+    async fn channel_info(&self, address: Address) -> Result<ChannelInfo, AuthError> {
+        let provider = ProviderBuilder::new().on_http(self.rpc_url.clone());
+        let payment_channel_contract = PaymentChannelContract::new(address, provider);
+
+        let contract_error = |e: alloy::contract::Error| AuthError::ContractError(e.to_string());
+
+        Ok(ChannelInfo {
+            address,
+            channel_id: payment_channel_contract
+                .channelId()
+                .call()
+                .await
+                .map_err(contract_error)?
+                ._0,
+            sender: payment_channel_contract
+                .sender()
+                .call()
+                .await
+                .map_err(contract_error)?
+                ._0,
+            recipient: payment_channel_contract
+                .recipient()
+                .call()
+                .await
+                .map_err(contract_error)?
+                ._0,
+            balance: payment_channel_contract
+                .getBalance()
+                .call()
+                .await
+                .map_err(contract_error)?
+                ._0,
+            expiration: payment_channel_contract
+                .expiration()
+                .call()
+                .await
+                .map_err(contract_error)?
+                ._0,
+            price: payment_channel_contract
+                .price()
+                .call()
+                .await
+                .map_err(contract_error)?
+                ._0,
+            token: payment_channel_contract
+                .token()
+                .call()
+                .await
+                .map_err(contract_error)?
+                ._0,
+        })
+    }
+
+    async fn factory_channel(
+        &self,
+        factory: Address,
+        channel_id: U256,
+    ) -> Result<Address, AuthError> {
+        let provider = ProviderBuilder::new().on_http(self.rpc_url.clone());
+        let factory_contract = ChannelFactoryContract::new(factory, provider);
+
+        Ok(factory_contract
+            .channels(channel_id)
+            .call()
+            .await
+            .map_err(|e| AuthError::ContractError(e.to_string()))?
+            ._0)
+    }
+
+    async fn close_channel(
+        &self,
+        signer: &PrivateKeySigner,
+        payment_channel: &PaymentChannel,
+        signature: &Signature,
+        raw_body: Bytes,
+    ) -> Result<FixedBytes<32>, AuthError> {
+        close_channel(
+            self.rpc_url.clone(),
+            signer,
+            payment_channel,
+            signature,
+            raw_body,
+        )
+        .await
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, AuthError> {
+        let provider = ProviderBuilder::new().on_http(self.rpc_url.clone());
+
+        provider
+            .get_logs(filter)
+            .await
+            .map_err(|e| AuthError::NetworkError(e.to_string()))
+    }
+
+    async fn block_number(&self) -> Result<u64, AuthError> {
+        let provider = ProviderBuilder::new().on_http(self.rpc_url.clone());
+
+        provider
+            .get_block_number()
+            .await
+            .map_err(|e| AuthError::NetworkError(e.to_string()))
+    }
+
+    async fn block_hash(&self, number: u64) -> Result<FixedBytes<32>, AuthError> {
+        let provider = ProviderBuilder::new().on_http(self.rpc_url.clone());
+
+        let block = provider
+            .get_block_by_number(
+                BlockNumberOrTag::Number(number),
+                BlockTransactionsKind::Hashes,
+            )
+            .await
+            .map_err(|e| AuthError::NetworkError(e.to_string()))?
+            .ok_or_else(|| AuthError::NetworkError(format!("Block {} not found", number)))?;
+
+        Ok(block.header.hash)
+    }
+}
@@ -2,52 +2,627 @@
 // It's the local channel state for the middleware on the server side on how to store the info and just work with it
 
 use std::{
-    collections::HashMap,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock as SyncRwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use alloy::{
-    contract::Error,
     network::EthereumWallet,
-    primitives::{Address, FixedBytes, U256},
+    primitives::{keccak256, Address, FixedBytes, U256},
     providers::ProviderBuilder,
     signers::{local::PrivateKeySigner, Signature},
     sol,
 };
 use alloy::{primitives::Bytes, transports::http::reqwest::Url};
-use tokio::sync::RwLock;
+use tokio::{
+    sync::{Notify, OnceCell, RwLock},
+    task::JoinHandle,
+};
 
-use crate::{error::AuthError, types::PaymentChannel};
+use crate::{
+    access::AccessPolicy,
+    audit::AuditLog,
+    chain::{AlloyBackend, ChainBackend},
+    config::Settings,
+    error::AuthError,
+    idempotency::IdempotencyCache,
+    types::{
+        ChannelAdjustment, ChannelEvent, ChannelInfo, ChannelRecord, ChannelStatus, ChannelVoucher,
+        MessageScheme, PaymentChannel,
+    },
+    utils::create_domain_body,
+};
 
 sol!(
-    #[allow(missing_docs)]
+    #[allow(missing_docs, clippy::too_many_arguments)]
     #[sol(rpc)]
     PaymentChannelContract,
     "src/abi/PaymentChannel.json"
 );
 
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    ChannelFactoryContract,
+    "src/abi/ChannelFactory.json"
+);
+
 #[derive(Clone)]
 pub struct ChannelState {
-    pub(crate) channels: Arc<RwLock<HashMap<U256, PaymentChannel>>>, // All the channels the current server has with other user
-    rate_limiter: Arc<RwLock<HashMap<Address, (u64, SystemTime)>>>,  // Rate limiter for the user
-    network_rpc_url: Url, // provider: Arc<dyn Provider>, // Provider to interact with the blockchain
+    pub(crate) channels: Arc<RwLock<HashMap<U256, ChannelRecord>>>, // All the channels the current server has with other user
+    rate_limiter: Arc<RwLock<HashMap<Address, (u64, SystemTime)>>>, // Rate limiter for the user
+    pub(crate) backend: Arc<dyn ChainBackend>,                      // Access to the blockchain
+    factory: Option<Address>, // ChannelFactory the channels must be registered in, if set
+    chain_id: Arc<OnceCell<u64>>, // Read from the backend on first use
+    legacy_messages: bool,    // Accept messages that aren't bound to the chain and the contract
+    typed_vouchers: bool,     // Accept EIP-712 vouchers, which the channel contract can't settle
+    request_binding: bool,    // Only accept messages signed over the canonical request
+    validations: Arc<Mutex<HashMap<U256, Arc<Notify>>>>, // Requests waiting on the validation of a new channel
+    idempotency: Option<IdempotencyCache>, // Outcomes of the requests sent with an idempotency key
+    signer: Option<PrivateKeySigner>,      // Server key, signs what the server attests to
+    audit_log: Option<AuditLog>,           // Every charge, hash chained
+    settings: Arc<SyncRwLock<Arc<Settings>>>, // Pricing, access and rate limits, swapped as a whole on reload
+    browser_mode: bool, // Answer CORS preflights and expose the pipegate headers
+    timestamp_window: u64, // How old a request's timestamp can be, in seconds
+    recipients: Vec<Address>, // Channels must pay one of these, if set
+    tokens: Vec<Address>, // Channels must be in one of these tokens, if set
+    blocked: Arc<SyncRwLock<HashSet<Address>>>, // Senders refused by an operator, whatever they pay
 }
 
 impl ChannelState {
     pub fn new(rpc_url: Url) -> Self {
+        Self::with_backend(Arc::new(AlloyBackend::new(rpc_url)))
+    }
+
+    pub fn with_backend(backend: Arc<dyn ChainBackend>) -> Self {
         Self {
             channels: Arc::new(RwLock::new(HashMap::new())),
             rate_limiter: Arc::new(RwLock::new(HashMap::new())),
-            network_rpc_url: rpc_url,
+            backend,
+            factory: None,
+            chain_id: Arc::new(OnceCell::new()),
+            legacy_messages: true,
+            typed_vouchers: false,
+            request_binding: false,
+            validations: Arc::new(Mutex::new(HashMap::new())),
+            idempotency: None,
+            signer: None,
+            audit_log: None,
+            settings: Arc::new(SyncRwLock::new(Arc::new(Settings::default()))),
+            browser_mode: false,
+            timestamp_window: 300,
+            recipients: Vec::new(),
+            tokens: Vec::new(),
+            blocked: Arc::new(SyncRwLock::new(HashSet::new())),
+        }
+    }
+
+    // Only accept channels registered in this ChannelFactory
+    pub fn with_factory(mut self, factory: Address) -> Self {
+        self.factory = Some(factory);
+        self
+    }
+
+    // Stop accepting legacy messages once the clients have moved to a domain separated scheme
+    pub fn with_legacy_messages(mut self, allowed: bool) -> Self {
+        self.legacy_messages = allowed;
+        self
+    }
+
+    // Accept EIP-712 vouchers, off by default
+    // The channel contract can't settle them, what is served against them can only be claimed with a later settleable voucher
+    pub fn with_typed_vouchers(mut self, enabled: bool) -> Self {
+        self.typed_vouchers = enabled;
+        self
+    }
+
+    // Require the clients to sign the canonical request, so vouchers can't be replayed against other endpoints
+    pub fn with_request_binding(mut self, required: bool) -> Self {
+        self.request_binding = required;
+        self
+    }
+
+    // Keep the outcome of the requests sent with an `Idempotency-Key` for `ttl`, and optionally their response
+    pub fn with_idempotency(mut self, ttl: Duration, cache_responses: bool) -> Self {
+        self.idempotency = Some(IdempotencyCache::new(ttl, cache_responses));
+        self
+    }
+
+    pub fn idempotency(&self) -> Option<&IdempotencyCache> {
+        self.idempotency.as_ref()
+    }
+
+    // Key the server signs channel state with, e.g. for the resync endpoint
+    pub fn with_signer(mut self, signer: PrivateKeySigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    pub fn signer(&self) -> Option<&PrivateKeySigner> {
+        self.signer.as_ref()
+    }
+
+    // Record every charge in the audit log
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit_log.as_ref()
+    }
+
+    // Routes served for free, and internal senders that aren't charged
+    pub fn with_access(self, access: AccessPolicy) -> Self {
+        let settings = Settings {
+            access,
+            ..(*self.settings()).clone()
+        };
+        self.with_settings(settings)
+    }
+
+    pub fn with_settings(self, settings: Settings) -> Self {
+        *self.settings.write().unwrap() = Arc::new(settings);
+        self
+    }
+
+    // The settings in use, requests keep the ones they started with even if reloaded meanwhile
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    // Swap the settings for the requests to come, recorded in the audit log. Returns the previous settings
+    pub fn reload_settings(&self, settings: Settings, source: &str) -> Arc<Settings> {
+        let summary = settings.summary();
+        let previous = std::mem::replace(&mut *self.settings.write().unwrap(), Arc::new(settings));
+        println!("Settings reloaded ({}): {}", source, summary);
+        self.record_change(format!("settings reloaded ({}): {}", source, summary));
+
+        previous
+    }
+
+    // Record a change made to the state, or an event worth keeping along with the charges, in the audit log if any
+    pub(crate) fn record_change(&self, change: String) {
+        if let Some(audit_log) = &self.audit_log {
+            if let Err(e) = audit_log.record_change(change) {
+                println!("Failed: Audit log write - Error {}", e);
+            }
+        }
+    }
+
+    // Refuse every request from `sender`, until unblocked, recorded in the audit log. Returns false if it was already blocked
+    pub fn block_sender(&self, sender: Address, source: &str) -> bool {
+        let blocked = self.blocked.write().unwrap().insert(sender);
+        if blocked {
+            println!("Sender {} blocked ({})", sender, source);
+            self.record_change(format!("sender {} blocked ({})", sender, source));
+        }
+        blocked
+    }
+
+    // Returns false if it wasn't blocked
+    pub fn unblock_sender(&self, sender: Address, source: &str) -> bool {
+        let unblocked = self.blocked.write().unwrap().remove(&sender);
+        if unblocked {
+            println!("Sender {} unblocked ({})", sender, source);
+            self.record_change(format!("sender {} unblocked ({})", sender, source));
         }
+        unblocked
+    }
+
+    pub fn is_blocked(&self, sender: Address) -> bool {
+        self.blocked.read().unwrap().contains(&sender)
+    }
+
+    pub fn blocked_senders(&self) -> Vec<Address> {
+        self.blocked.read().unwrap().iter().copied().collect()
+    }
+
+    // For the dApps calling the API from the browser, see `cors`
+    pub fn with_browser_mode(mut self, enabled: bool) -> Self {
+        self.browser_mode = enabled;
+        self
+    }
+
+    pub fn browser_mode(&self) -> bool {
+        self.browser_mode
+    }
+
+    // Refuse the requests signed longer than `window` ago
+    pub fn with_timestamp_window(mut self, window: Duration) -> Self {
+        self.timestamp_window = window.as_secs();
+        self
+    }
+
+    pub fn timestamp_window(&self) -> u64 {
+        self.timestamp_window
+    }
+
+    // At most `requests` per sender every `window`
+    pub fn with_rate_limit(self, requests: u64, window: Duration) -> Self {
+        let settings = Settings {
+            rate_limit: requests,
+            rate_limit_window: window,
+            ..(*self.settings()).clone()
+        };
+        self.with_settings(settings)
+    }
+
+    // Only accept the channels paying one of these recipients
+    pub fn with_recipients(mut self, recipients: Vec<Address>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+
+    // Only accept the channels funded with one of these tokens
+    pub fn with_tokens(mut self, tokens: Vec<Address>) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    pub fn factory(&self) -> Option<Address> {
+        self.factory
+    }
+
+    pub fn accepts_legacy_messages(&self) -> bool {
+        self.legacy_messages
+    }
+
+    pub fn accepts_typed_vouchers(&self) -> bool {
+        self.typed_vouchers
+    }
+
+    pub fn requires_request_binding(&self) -> bool {
+        self.request_binding
+    }
+
+    pub fn backend(&self) -> &Arc<dyn ChainBackend> {
+        &self.backend
+    }
+
+    pub async fn chain_id(&self) -> Result<u64, AuthError> {
+        self.chain_id
+            .get_or_try_init(|| self.backend.chain_id())
+            .await
+            .copied()
     }
 
     pub async fn get_channel(&self, channel_id: U256) -> Option<PaymentChannel> {
+        let channels = self.channels.read().await;
+        channels
+            .get(&channel_id)
+            .map(|record| record.channel.clone())
+    }
+
+    pub async fn get_status(&self, channel_id: U256) -> Option<ChannelStatus> {
+        let channels = self.channels.read().await;
+        channels.get(&channel_id).map(|record| record.status)
+    }
+
+    pub async fn get_record(&self, channel_id: U256) -> Option<ChannelRecord> {
         let channels = self.channels.read().await;
         channels.get(&channel_id).cloned()
     }
 
+    // Snapshot of every channel, ordered by id
+    pub async fn records(&self) -> Vec<ChannelRecord> {
+        let channels = self.channels.read().await;
+        let mut records: Vec<_> = channels.values().cloned().collect();
+        records.sort_by_key(|record| record.channel.channel_id);
+        records
+    }
+
+    // Correct a channel by hand, e.g. after a refund made outside of the channel. Recorded in the audit log
+    pub async fn adjust_channel(
+        &self,
+        channel_id: U256,
+        adjustment: &ChannelAdjustment,
+        source: &str,
+    ) -> Result<ChannelRecord, AuthError> {
+        let mut channels = self.channels.write().await;
+        let record = channels
+            .get_mut(&channel_id)
+            .ok_or(AuthError::ChannelNotFound)?;
+        record.adjust(adjustment);
+
+        let change = format!(
+            "channel {} adjusted ({}): {}",
+            channel_id,
+            source,
+            serde_json::to_string(adjustment).unwrap()
+        );
+        println!("{}", change);
+        self.record_change(change);
+
+        Ok(record.clone())
+    }
+
+    // Notified once the channel being validated is either active or dropped
+    pub(crate) fn validation(&self, channel_id: U256) -> Arc<Notify> {
+        let mut validations = self.validations.lock().unwrap();
+        validations.entry(channel_id).or_default().clone()
+    }
+
+    pub(crate) fn validated(&self, channel_id: U256) {
+        if let Some(validation) = self.validations.lock().unwrap().remove(&channel_id) {
+            validation.notify_waiters();
+        }
+    }
+
+    // Move the channel to the next status if the transition is allowed, returns the previous status
+    pub async fn transition(
+        &self,
+        channel_id: U256,
+        next: ChannelStatus,
+    ) -> Result<ChannelStatus, AuthError> {
+        let mut channels = self.channels.write().await;
+        let record = channels
+            .get_mut(&channel_id)
+            .ok_or(AuthError::ChannelNotFound)?;
+
+        record.transition(next)
+    }
+
+    // Apply an onchain event to the local channel state
+    // Safe to apply the same event more than once, e.g. when the indexer rescans blocks after a reorg
+    // Returns true if the local state was changed
+    pub async fn apply_event(&self, event: &ChannelEvent) -> bool {
+        let mut channels = self.channels.write().await;
+
+        let record = match channels.get_mut(&event.channel_id()) {
+            Some(record) if record.channel.address == event.address() => record,
+            _ => return false,
+        };
+
+        match event {
+            ChannelEvent::Deposit { new_balance, .. } => {
+                // Credit only the part of the deposit we haven't seen yet
+                // Channels still being validated will read the balance from the contract anyway
+                if record.status == ChannelStatus::PendingValidation
+                    || *new_balance <= record.deposited
+                {
+                    return false;
+                }
+                let credit = record.credit_deposit(*new_balance);
+                println!(
+                    "Channel {} topped up by {}, new balance: {}",
+                    event.channel_id(),
+                    credit,
+                    record.channel.balance
+                );
+                true
+            }
+            ChannelEvent::ExpirationExtended { expiration, .. } => {
+                if *expiration <= record.channel.expiration {
+                    return false;
+                }
+                record.channel.expiration = *expiration;
+                if record.status == ChannelStatus::Expired {
+                    let _ = record.transition(ChannelStatus::Active);
+                }
+                println!(
+                    "Channel {} extended until {}",
+                    event.channel_id(),
+                    expiration
+                );
+                true
+            }
+            ChannelEvent::Closed { .. } | ChannelEvent::TimeoutClaimed { .. } => {
+                // The channel contract is drained, nothing left to serve requests against
+                if record.transition(ChannelStatus::Closed).is_err() {
+                    return false;
+                }
+                println!("Channel {} closed onchain", event.channel_id());
+                true
+            }
+        }
+    }
+
+    // Mark the channel as closed, e.g. after settling it ourselves
+    // The record is kept around so that further requests get a `ChannelClosed` error, until it's pruned
+    pub async fn mark_closed(&self, channel_id: U256) -> Result<(), AuthError> {
+        match self.transition(channel_id, ChannelStatus::Closed).await {
+            Ok(_) | Err(AuthError::InvalidTransition(ChannelStatus::Closed, _)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Settle the channel onchain with the sender's signed voucher, and stop serving requests against it
+    pub async fn settle_channel(
+        &self,
+        signer: &PrivateKeySigner,
+        payment_channel: &PaymentChannel,
+        signature: &Signature,
+        raw_body: Bytes,
+    ) -> Result<FixedBytes<32>, AuthError> {
+        // Closing pays out the deposit minus the claimed balance, never more than was served
+        if let Some(record) = self.get_record(payment_channel.channel_id).await {
+            let payout = record.deposited.saturating_sub(payment_channel.balance);
+            if payout > record.served {
+                println!(
+                    "Failed: Voucher pays out {}, only {} was served",
+                    payout, record.served
+                );
+                return Err(AuthError::ClaimExceedsServed);
+            }
+        }
+
+        // Stop serving requests while the close transaction is in flight
+        // The channel might never have been used through this server, then there is nothing to track
+        let previous = match self
+            .transition(payment_channel.channel_id, ChannelStatus::Settling)
+            .await
+        {
+            Ok(previous) => Some(previous),
+            Err(AuthError::ChannelNotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+        let result = self
+            .backend
+            .close_channel(signer, payment_channel, signature, raw_body)
+            .await;
+
+        match result {
+            Ok(tx_hash) => {
+                println!(
+                    "Channel {} settled: {}",
+                    payment_channel.channel_id, tx_hash
+                );
+                if previous.is_some() {
+                    self.mark_closed(payment_channel.channel_id).await?;
+                }
+                Ok(tx_hash)
+            }
+            Err(e) => {
+                // Back to where it was, the channel can still be used
+                if let Some(previous) = previous {
+                    self.transition(payment_channel.channel_id, previous)
+                        .await?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // The `rawBody` to close the channel with, for a voucher signed with `scheme` over `body`
+    // `body` is the signed payload, see `SignedRequest::payload`
+    pub async fn settlement_body(
+        &self,
+        scheme: MessageScheme,
+        payment_channel: &PaymentChannel,
+        body: &[u8],
+    ) -> Result<Bytes, AuthError> {
+        match scheme {
+            MessageScheme::Legacy => Ok(Bytes::copy_from_slice(body)),
+            // Only accepted with a factory set, see `verify_with_settings`
+            MessageScheme::Domain => Ok(create_domain_body(
+                self.chain_id().await?,
+                self.factory.ok_or(AuthError::InvalidMessage)?,
+                payment_channel.address,
+                body,
+            )
+            .into()),
+            MessageScheme::BodyHash => Ok(Bytes::copy_from_slice(keccak256(body).as_slice())),
+            // The channel contract only verifies personal messages
+            MessageScheme::Eip712 => Err(AuthError::InvalidMessage),
+        }
+    }
+
+    // Drop the channels that were closed, or expired without anything charged, more than `retention` ago
+    // Channels with charges not settled yet are kept whatever their status, their voucher is what pays them out
+    // Returns the number of channels removed
+    pub async fn prune_channels(&self, retention: Duration) -> usize {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let cutoff = now.saturating_sub(retention.as_secs());
+
+        let mut channels = self.channels.write().await;
+        let before = channels.len();
+
+        channels.retain(|_, record| match record.status {
+            ChannelStatus::Closed => record.updated_at > cutoff,
+            _ => !record.served.is_zero() || record.channel.expiration > U256::from(cutoff),
+        });
+
+        before - channels.len()
+    }
+
+    // Prune the stale channels in the background, every `interval`
+    pub fn spawn_pruner(&self, interval: Duration, retention: Duration) -> JoinHandle<()> {
+        let state = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let removed = state.prune_channels(retention).await;
+                if removed > 0 {
+                    println!("Pruned {} stale channels", removed);
+                }
+            }
+        })
+    }
+
+    // Close the channels whose kept voucher pays out at least `threshold`, or that expire within `before_expiry`,
+    // before the sender can take the deposit back. Channels without a settleable voucher are left alone
+    // Returns the channels settled
+    pub async fn settle_due_channels(
+        &self,
+        signer: &PrivateKeySigner,
+        threshold: Option<U256>,
+        before_expiry: Option<Duration>,
+    ) -> Vec<U256> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let due: Vec<ChannelVoucher> = self
+            .records()
+            .await
+            .into_iter()
+            .filter(|record| {
+                matches!(
+                    record.status,
+                    ChannelStatus::Active | ChannelStatus::Expired
+                )
+            })
+            .filter_map(|record| {
+                let voucher = record.voucher?;
+                let payout = record
+                    .deposited
+                    .saturating_sub(voucher.payment_channel.balance);
+                let over_threshold = threshold.is_some_and(|threshold| payout >= threshold);
+                let expiring = before_expiry.is_some_and(|before_expiry| {
+                    record.channel.expiration <= U256::from(now + before_expiry.as_secs())
+                });
+                (!payout.is_zero() && (over_threshold || expiring)).then_some(voucher)
+            })
+            .collect();
+
+        let mut settled = Vec::new();
+        for voucher in due {
+            let channel_id = voucher.payment_channel.channel_id;
+            match self
+                .settle_channel(
+                    signer,
+                    &voucher.payment_channel,
+                    &voucher.signature,
+                    voucher.raw_body,
+                )
+                .await
+            {
+                Ok(_) => settled.push(channel_id),
+                Err(e) => println!("Failed: Settle channel {} - Error {}", channel_id, e),
+            }
+        }
+        settled
+    }
+
+    // Settle the channels due in the background, every `interval`
+    pub fn spawn_settler(
+        &self,
+        signer: PrivateKeySigner,
+        interval: Duration,
+        threshold: Option<U256>,
+        before_expiry: Option<Duration>,
+    ) -> JoinHandle<()> {
+        let state = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let settled = state
+                    .settle_due_channels(&signer, threshold, before_expiry)
+                    .await;
+                if !settled.is_empty() {
+                    println!("Settled {} channels", settled.len());
+                }
+            }
+        })
+    }
+
     // verification method
 
     pub async fn verify_signature(
@@ -55,11 +630,21 @@ impl ChannelState {
         payment_channel: &PaymentChannel,
         signature: &Signature,
         message: &[u8],
+        scheme: MessageScheme,
     ) -> Result<(), AuthError> {
         // self.network.verify_signature(signature, message).await
 
         // Network logic to verify the signature, could be a simple ECDSA verification
-        let recovered = signature.recover_address_from_msg(message);
+        // The legacy message is signed as a personal message, the EIP-712 one is already the final hash
+        let recovered = match scheme {
+            MessageScheme::Legacy | MessageScheme::Domain | MessageScheme::BodyHash => {
+                signature.recover_address_from_msg(message)
+            }
+            MessageScheme::Eip712 => match <[u8; 32]>::try_from(message) {
+                Ok(hash) => signature.recover_address_from_prehash(&hash.into()),
+                Err(_) => return Err(AuthError::InvalidMessage),
+            },
+        };
         println!("Recovered address: {:?}", recovered);
 
         // Match the recovered address with the one in the channel state
@@ -72,89 +657,83 @@ impl ChannelState {
         }
     }
 
-    // Validating all the information of the channel from the onchain contract for the first time, before the channel is used
+    // Validating all the information of the channel from the onchain contract, before the channel is used for the first time
+    // and again whenever the sender claims a top-up or an extension. Returns the balance held by the contract
     pub async fn validate_channel(
         &self,
         payment_channel: &PaymentChannel,
-    ) -> Result<(), AuthError> {
+    ) -> Result<U256, AuthError> {
         // self.network.validate_channel(channel_id, balance).await
-        let provider = ProviderBuilder::new().on_http(self.network_rpc_url.clone());
+        let info = self.read_channel(payment_channel.address).await?;
 
-        let payment_channel_contract =
-            PaymentChannelContract::new(payment_channel.address, provider);
+        println!("Balance: {}", info.balance);
 
-        let balance_value = payment_channel_contract
-            .getBalance()
-            .call()
-            .await
-            .unwrap()
-            ._0;
-
-        let balance = U256::from(balance_value);
-
-        println!("Balance: {}", balance);
-
-        // If the balance is less than the balance in the local state, return an error
-        if payment_channel.balance < balance {
+        // If the contract holds less than the balance claimed by the sender, return an error
+        if payment_channel.balance > info.balance {
             return Err(AuthError::InsufficientBalance);
         }
 
-        let expiration_value = payment_channel_contract
-            .expiration()
-            .call()
-            .await
-            .unwrap()
-            ._0;
-
-        let expiration = U256::from(expiration_value);
-
-        println!("Expiration: {}", expiration);
+        println!("Expiration: {}", info.expiration);
 
-        if payment_channel.expiration != expiration {
+        // The claimed expiration can lag behind the contract, but can never be later than it
+        if payment_channel.expiration > info.expiration {
             return Err(AuthError::Expired);
         }
 
         // Verify the channelID from the contract
-        let channel_id_value = payment_channel_contract
-            .channelId()
-            .call()
-            .await
-            .unwrap()
-            ._0;
-        let channel_id = U256::from(channel_id_value);
-
-        println!("Channel ID: {}", channel_id);
+        println!("Channel ID: {}", info.channel_id);
 
-        if payment_channel.channel_id != channel_id {
+        if payment_channel.channel_id != info.channel_id {
             return Err(AuthError::InvalidChannel);
         }
 
         // Verify sender and recipient from the contract
-        let sender_value = payment_channel_contract.sender().call().await.unwrap()._0;
+        if payment_channel.sender != info.sender {
+            return Err(AuthError::InvalidChannel);
+        }
 
-        if payment_channel.sender != sender_value {
+        if payment_channel.recipient != info.recipient {
             return Err(AuthError::InvalidChannel);
         }
 
-        let recipient_value = payment_channel_contract
-            .recipient()
-            .call()
-            .await
-            .unwrap()
-            ._0;
+        if !self.recipients.is_empty() && !self.recipients.contains(&info.recipient) {
+            println!("Failed: Recipient {} not accepted", info.recipient);
+            return Err(AuthError::InvalidChannel);
+        }
 
-        if payment_channel.recipient != recipient_value {
+        if !self.tokens.is_empty() && !self.tokens.contains(&info.token) {
+            println!("Failed: Token {} not accepted", info.token);
             return Err(AuthError::InvalidChannel);
         }
 
-        Ok(())
+        // Verify the contract is the one the factory created for the channel ID
+        if let Some(factory) = self.factory {
+            let address = self
+                .backend
+                .factory_channel(factory, payment_channel.channel_id)
+                .await?;
+
+            if payment_channel.address != address {
+                return Err(AuthError::InvalidChannel);
+            }
+        }
+
+        Ok(info.balance)
+    }
+
+    // Read the current state of a channel contract
+    pub async fn read_channel(&self, address: Address) -> Result<ChannelInfo, AuthError> {
+        self.backend.channel_info(address).await
     }
 
     // rate limiter method
     // ✅
-    pub(crate) async fn check_rate_limit(&self, sender: Address) -> Result<(), AuthError> {
-        const RATE_LIMIT: u64 = 100; // 100 requests
-        const WINDOW: u64 = 60; // Every 60 seconds
+    pub(crate) async fn check_rate_limit(
+        &self,
+        sender: Address,
+        settings: &Settings,
+    ) -> Result<(), AuthError> {
+        let (rate_limit, window) = (settings.rate_limit, settings.rate_limit_window.as_secs());
 
         let mut rate_limits = self.rate_limiter.write().await;
         let now = SystemTime::now()
@@ -166,11 +745,11 @@ impl ChannelState {
 
         let last_reset_secs = last_reset.duration_since(UNIX_EPOCH).unwrap().as_secs();
 
-        if now - last_reset_secs >= WINDOW {
+        if now - last_reset_secs >= window {
             *count = 1;
             *last_reset = SystemTime::now();
             Ok(())
-        } else if *count >= RATE_LIMIT {
+        } else if *count >= rate_limit {
             Err(AuthError::RateLimitExceeded)
         } else {
             *count += 1;
@@ -180,17 +759,18 @@ impl ChannelState {
 }
 
 // Close the channel to withdraw the funds
+// `raw_body` is what the voucher was signed over, see `ChannelState::settlement_body`
 pub async fn close_channel(
     rpc_url: Url,
-    private_key: &str,
+    signer: &PrivateKeySigner,
     payment_channel: &PaymentChannel,
     signature: &Signature,
     raw_body: Bytes,
-) -> Result<FixedBytes<32>, Error> {
-    let signer: PrivateKeySigner = private_key.parse().expect("Invalid private key");
-    let wallet = EthereumWallet::from(signer);
+) -> Result<FixedBytes<32>, AuthError> {
+    let wallet = EthereumWallet::from(signer.clone());
 
     let provider = ProviderBuilder::new()
+        .with_recommended_fillers()
         .wallet(wallet)
         .on_http(rpc_url.clone());
 
@@ -204,9 +784,57 @@ pub async fn close_channel(
             Bytes::from(signature.as_bytes()),
         )
         .send()
-        .await?
+        .await
+        .map_err(|e| AuthError::ContractError(e.to_string()))?
+        .watch()
+        .await
+        .map_err(|e| AuthError::ContractError(e.to_string()))?;
+
+    Ok(tx_hash)
+}
+
+// Register the price per request of the signer, as a recipient, in the ChannelFactory
+// Channels created for the recipient from then on are priced with it
+pub async fn register_price(
+    rpc_url: Url,
+    signer: &PrivateKeySigner,
+    factory: Address,
+    price: U256,
+) -> Result<FixedBytes<32>, AuthError> {
+    let wallet = EthereumWallet::from(signer.clone());
+
+    let provider = ProviderBuilder::new()
+        .with_recommended_fillers()
+        .wallet(wallet)
+        .on_http(rpc_url.clone());
+
+    let factory_contract = ChannelFactoryContract::new(factory, provider);
+
+    let tx_hash = factory_contract
+        .register(price)
+        .send()
+        .await
+        .map_err(|e| AuthError::ContractError(e.to_string()))?
         .watch()
-        .await?;
+        .await
+        .map_err(|e| AuthError::ContractError(e.to_string()))?;
 
     Ok(tx_hash)
 }
+
+// Price registered by the recipient in the ChannelFactory, zero if it never registered
+pub async fn registered_price(
+    rpc_url: Url,
+    factory: Address,
+    recipient: Address,
+) -> Result<U256, AuthError> {
+    let provider = ProviderBuilder::new().on_http(rpc_url);
+    let factory_contract = ChannelFactoryContract::new(factory, provider);
+
+    Ok(factory_contract
+        .pricing(recipient)
+        .call()
+        .await
+        .map_err(|e| AuthError::ContractError(e.to_string()))?
+        ._0)
+}
@@ -0,0 +1,512 @@
+// `pipegate` command line, for the onchain operations of the providers
+// Registers the price in the ChannelFactory, reads a channel, settles one with a voucher saved from the server, and
+// checks the RPC, key and factory set up. Options come from the flags, the environment, then the config file
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    primitives::{Address, FixedBytes, U256},
+    providers::{Provider, ProviderBuilder},
+    signers::local::PrivateKeySigner,
+    transports::http::reqwest::Url,
+};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    chain::{AlloyBackend, ChainBackend},
+    channel::{close_channel, register_price, registered_price},
+    config::{PipegateConfig, CONFIG_PATH_VAR},
+    error::{AuthError, CliError, ConfigError},
+    types::{ChannelInfo, ChannelVoucher},
+};
+
+// Read for the key when neither `--key-env` nor `server.signer_key_env` is set, as in the examples
+const DEFAULT_KEY_ENV: &str = "PRIVATE_KEY";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "pipegate",
+    version,
+    about = "Onchain operations for pipegate providers"
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub options: Options,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[arg(long, global = true, env = CONFIG_PATH_VAR, help = "Config file, for what isn't set otherwise")]
+    pub config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "PIPEGATE_RPC_URL",
+        help = "JSON-RPC endpoint"
+    )]
+    pub rpc_url: Option<Url>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "PIPEGATE_FACTORY",
+        help = "ChannelFactory contract"
+    )]
+    pub factory: Option<Address>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Environment variable holding the private key of the recipient [default: PRIVATE_KEY]"
+    )]
+    pub key_env: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Register the price per request in the ChannelFactory")]
+    Register {
+        #[arg(
+            long,
+            help = "In the smallest unit of the token, the default price of the config if not set"
+        )]
+        price: Option<U256>,
+    },
+
+    #[command(about = "Read the state of a channel from its contract")]
+    Inspect {
+        #[arg(help = "Channel contract", required_unless_present = "channel_id")]
+        address: Option<Address>,
+
+        #[arg(
+            long,
+            conflicts_with = "address",
+            help = "Channel id, looked up in the ChannelFactory"
+        )]
+        channel_id: Option<U256>,
+    },
+
+    #[command(about = "Close a channel with a voucher saved from the server")]
+    Settle {
+        #[arg(help = "JSON voucher, e.g. from `GET /channels/:id/voucher` of the admin API")]
+        voucher: PathBuf,
+    },
+
+    #[command(about = "Check the RPC, the key and the ChannelFactory")]
+    Doctor {
+        #[arg(long, help = "Chain id the RPC must be on")]
+        chain_id: Option<u64>,
+    },
+}
+
+// Options resolved from the flags, the environment and the config file
+#[derive(Clone, Debug)]
+pub struct Context {
+    pub rpc_url: Url,
+    pub factory: Option<Address>,
+    pub key_env: String, // Environment variable holding the key, never the key itself
+    pub recipients: Vec<Address>, // From the config, the key should be one of them
+    pub default_price: Option<U256>, // From the config
+}
+
+impl Options {
+    pub fn context(&self) -> Result<Context, ConfigError> {
+        let config = self
+            .config
+            .as_ref()
+            .map(PipegateConfig::from_file)
+            .transpose()?;
+
+        let rpc_url = self
+            .rpc_url
+            .clone()
+            .or_else(|| config.as_ref().map(|config| config.network.rpc_url.clone()))
+            .ok_or_else(|| {
+                ConfigError::invalid("network.rpc_url", "not set, pass --rpc-url or --config")
+            })?;
+        let factory = self
+            .factory
+            .or_else(|| config.as_ref().and_then(|config| config.network.factory));
+        let key_env = self
+            .key_env
+            .clone()
+            .or_else(|| {
+                config
+                    .as_ref()
+                    .and_then(|config| config.server.signer_key_env.clone())
+            })
+            .unwrap_or_else(|| DEFAULT_KEY_ENV.to_string());
+
+        Ok(Context {
+            rpc_url,
+            factory,
+            key_env,
+            recipients: config
+                .as_ref()
+                .map(|config| config.network.recipients.clone())
+                .unwrap_or_default(),
+            default_price: config.as_ref().map(|config| config.pricing.default),
+        })
+    }
+}
+
+impl Context {
+    // The key of the recipient, from the environment
+    pub fn signer(&self) -> Result<PrivateKeySigner, ConfigError> {
+        env::var(&self.key_env)
+            .map_err(|_| ConfigError::invalid("key", format!("{} not set", self.key_env)))?
+            .parse()
+            .map_err(|_| {
+                ConfigError::invalid("key", format!("{} is not a private key", self.key_env))
+            })
+    }
+
+    fn factory(&self) -> Result<Address, ConfigError> {
+        self.factory.ok_or_else(|| {
+            ConfigError::invalid("network.factory", "not set, pass --factory or --config")
+        })
+    }
+
+    fn backend(&self) -> AlloyBackend {
+        AlloyBackend::new(self.rpc_url.clone())
+    }
+}
+
+pub async fn run(cli: Cli) -> Result<(), CliError> {
+    let context = cli.options.context()?;
+
+    match cli.command {
+        Command::Register { price } => {
+            let (price, tx_hash) = register(&context, price).await?;
+            println!("Registered price {}: {}", price, tx_hash);
+        }
+        Command::Inspect {
+            address,
+            channel_id,
+        } => {
+            let info = inspect(&context, address, channel_id).await?;
+            println!("{}", serde_json::to_string_pretty(&info).unwrap());
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            match info.expiration.checked_sub(U256::from(now)) {
+                Some(left) if !left.is_zero() => println!("Expires in {}s", left),
+                _ => println!("Expired, the sender can reclaim the balance"),
+            }
+        }
+        Command::Settle { voucher } => {
+            let voucher = read_voucher(&voucher)?;
+            let tx_hash = settle(&context, &voucher).await?;
+            println!(
+                "Channel {} settled: {}",
+                voucher.payment_channel.channel_id, tx_hash
+            );
+        }
+        Command::Doctor { chain_id } => {
+            let checks = doctor(&context, chain_id).await;
+            for check in &checks {
+                println!("{:<7} {:<10} {}", check.status, check.name, check.detail);
+            }
+
+            let failed = checks
+                .iter()
+                .filter(|check| check.status == CheckStatus::Failed)
+                .count();
+            if failed > 0 {
+                return Err(CliError::Doctor(failed));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Register `price`, or the default price of the config, for the key in the factory. Returns the price and the transaction
+pub async fn register(
+    context: &Context,
+    price: Option<U256>,
+) -> Result<(U256, FixedBytes<32>), CliError> {
+    let price = price.or(context.default_price).ok_or_else(|| {
+        ConfigError::invalid("pricing.default", "not set, pass --price or --config")
+    })?;
+    if price.is_zero() {
+        return Err(CliError::Refused("The price must not be zero".to_string()));
+    }
+
+    let tx_hash = register_price(
+        context.rpc_url.clone(),
+        &context.signer()?,
+        context.factory()?,
+        price,
+    )
+    .await?;
+
+    Ok((price, tx_hash))
+}
+
+// Read the channel at `address`, or registered in the factory under `channel_id`
+pub async fn inspect(
+    context: &Context,
+    address: Option<Address>,
+    channel_id: Option<U256>,
+) -> Result<ChannelInfo, CliError> {
+    let backend = context.backend();
+
+    let address = match (address, channel_id) {
+        (Some(address), _) => address,
+        (None, Some(channel_id)) => {
+            let address = backend
+                .factory_channel(context.factory()?, channel_id)
+                .await?;
+            if address == Address::ZERO {
+                return Err(AuthError::ChannelNotFound.into());
+            }
+            address
+        }
+        (None, None) => {
+            return Err(ConfigError::invalid("channel", "pass an address or --channel-id").into())
+        }
+    };
+
+    Ok(backend.channel_info(address).await?)
+}
+
+pub fn read_voucher(path: &Path) -> Result<ChannelVoucher, ConfigError> {
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| ConfigError::Parse(format!("{}: {}", path.display(), e)))
+}
+
+// Close the channel with the voucher, checked against the contract first so that nothing is sent if it would revert
+pub async fn settle(
+    context: &Context,
+    voucher: &ChannelVoucher,
+) -> Result<FixedBytes<32>, CliError> {
+    if !voucher.scheme.is_settleable() {
+        return Err(CliError::Refused(
+            "EIP-712 vouchers can't be verified by the channel contract".to_string(),
+        ));
+    }
+
+    let signer = context.signer()?;
+    let payment_channel = &voucher.payment_channel;
+    let info = context
+        .backend()
+        .channel_info(payment_channel.address)
+        .await?;
+
+    if info.channel_id != payment_channel.channel_id {
+        return Err(CliError::Refused(format!(
+            "{} is channel {}, the voucher is for channel {}",
+            info.address, info.channel_id, payment_channel.channel_id
+        )));
+    }
+    if info.recipient != signer.address() {
+        return Err(CliError::Refused(format!(
+            "{} is not the recipient of channel {}, {} is",
+            signer.address(),
+            info.channel_id,
+            info.recipient
+        )));
+    }
+    if info.balance.is_zero() {
+        return Err(CliError::Refused(format!(
+            "Channel {} has no balance left, already closed",
+            info.channel_id
+        )));
+    }
+
+    let tx_hash = close_channel(
+        context.rpc_url.clone(),
+        &signer,
+        payment_channel,
+        &voucher.signature,
+        voucher.raw_body.clone(),
+    )
+    .await?;
+
+    Ok(tx_hash)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Failed,
+}
+
+impl std::fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Failed => "failed",
+        };
+        f.pad(status)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+// Check the RPC is reachable and on the expected chain, the key is usable, and the factory knows the price
+// The checks needing the RPC are skipped if it's unreachable
+pub async fn doctor(context: &Context, expected_chain_id: Option<u64>) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let rpc_ok = match context.backend().chain_id().await {
+        Ok(chain_id) if expected_chain_id.is_some_and(|expected| expected != chain_id) => {
+            checks.push(Check::new(
+                "rpc",
+                CheckStatus::Failed,
+                format!(
+                    "{} is on chain {}, expected {}",
+                    context.rpc_url,
+                    chain_id,
+                    expected_chain_id.unwrap()
+                ),
+            ));
+            false
+        }
+        Ok(chain_id) => {
+            checks.push(Check::new(
+                "rpc",
+                CheckStatus::Ok,
+                format!("{} is on chain {}", context.rpc_url, chain_id),
+            ));
+            true
+        }
+        Err(e) => {
+            checks.push(Check::new(
+                "rpc",
+                CheckStatus::Failed,
+                format!("{} unreachable: {}", context.rpc_url, e),
+            ));
+            false
+        }
+    };
+
+    let signer = match context.signer() {
+        Ok(signer) => {
+            checks.push(Check::new(
+                "key",
+                CheckStatus::Ok,
+                format!("{} holds {}", context.key_env, signer.address()),
+            ));
+            Some(signer)
+        }
+        Err(e) => {
+            checks.push(Check::new("key", CheckStatus::Failed, e.to_string()));
+            None
+        }
+    };
+
+    if let Some(signer) = &signer {
+        if !context.recipients.is_empty() && !context.recipients.contains(&signer.address()) {
+            checks.push(Check::new(
+                "recipient",
+                CheckStatus::Warning,
+                format!("{} is not in network.recipients", signer.address()),
+            ));
+        }
+
+        if rpc_ok {
+            let provider = ProviderBuilder::new().on_http(context.rpc_url.clone());
+            match provider.get_balance(signer.address()).await {
+                Ok(balance) if balance.is_zero() => checks.push(Check::new(
+                    "gas",
+                    CheckStatus::Warning,
+                    format!("{} has nothing to pay for gas with", signer.address()),
+                )),
+                Ok(balance) => checks.push(Check::new(
+                    "gas",
+                    CheckStatus::Ok,
+                    format!("{} wei", balance),
+                )),
+                Err(e) => checks.push(Check::new("gas", CheckStatus::Failed, e.to_string())),
+            }
+        }
+    }
+
+    match context.factory {
+        None => checks.push(Check::new(
+            "factory",
+            CheckStatus::Warning,
+            "not set, channels from any factory are accepted",
+        )),
+        Some(factory) if rpc_ok => {
+            let recipient = signer
+                .as_ref()
+                .map(|signer| signer.address())
+                .unwrap_or_default();
+            let check = match registered_price(context.rpc_url.clone(), factory, recipient).await {
+                Err(e) => Check::new(
+                    "factory",
+                    CheckStatus::Failed,
+                    format!("{} is not a ChannelFactory: {}", factory, e),
+                ),
+                Ok(_) if signer.is_none() => Check::new(
+                    "factory",
+                    CheckStatus::Ok,
+                    format!("{} is a ChannelFactory", factory),
+                ),
+                Ok(price) if price.is_zero() => Check::new(
+                    "factory",
+                    CheckStatus::Warning,
+                    format!(
+                        "no price registered in {}, run `pipegate register`",
+                        factory
+                    ),
+                ),
+                Ok(price)
+                    if context
+                        .default_price
+                        .is_some_and(|default| default != price) =>
+                {
+                    Check::new(
+                        "factory",
+                        CheckStatus::Warning,
+                        format!(
+                            "price {} registered in {}, the config charges {}",
+                            price,
+                            factory,
+                            context.default_price.unwrap()
+                        ),
+                    )
+                }
+                Ok(price) => Check::new(
+                    "factory",
+                    CheckStatus::Ok,
+                    format!("price {} registered in {}", price, factory),
+                ),
+            };
+            checks.push(check);
+        }
+        Some(_) => {}
+    }
+
+    checks
+}
@@ -0,0 +1,732 @@
+// Configuration of the whole middleware, from a TOML file with environment overrides
+// `PipegateConfig::build` validates it and sets up the `ChannelState`, the middleware layer comes from the built `Pipegate`
+
+use std::{
+    env, fs,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use alloy::{
+    primitives::{Address, U256},
+    signers::local::PrivateKeySigner,
+    transports::http::reqwest::Url,
+};
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::{FromFnLayer, Next},
+    response::Response,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+
+use crate::{
+    access::{matches_path, AccessPolicy},
+    admin::Admin,
+    audit::AuditLog,
+    channel::ChannelState,
+    error::ConfigError,
+    indexer::{ChannelIndexer, IndexerConfig},
+    middleware::middleware_with_settings,
+    reconcile::spawn_reconciler,
+};
+
+// Read by `PipegateConfig::load`
+pub const CONFIG_PATH_VAR: &str = "PIPEGATE_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "pipegate.toml";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipegateConfig {
+    #[serde(default)]
+    pub server: ServerConfig,
+    pub network: NetworkConfig,
+    pub pricing: PricingConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub settlement: SettlementConfig,
+    pub indexer: Option<IndexingConfig>, // Channels aren't indexed if not set
+    pub admin: Option<AdminConfig>,      // No admin API if not set
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    pub browser_mode: bool,
+    pub free_paths: Vec<String>, // Exact paths, or prefixes ending with `*`
+    pub allowed_senders: Vec<Address>, // Internal services, see `access`
+    pub signer_key_env: Option<String>, // Environment variable holding the server key, never the key itself
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            browser_mode: false,
+            free_paths: Vec::new(),
+            allowed_senders: Vec::new(),
+            signer_key_env: None,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    #[serde_as(as = "DisplayFromStr")]
+    pub rpc_url: Url,
+
+    #[serde(default)]
+    pub factory: Option<Address>,
+
+    #[serde(default)]
+    pub recipients: Vec<Address>, // Accepted channel recipients, any if empty
+
+    #[serde(default)]
+    pub tokens: Vec<Address>, // Accepted channel tokens, any if empty
+
+    #[serde(default = "default_true")]
+    pub legacy_messages: bool,
+
+    #[serde(default)]
+    pub typed_vouchers: bool, // EIP-712 vouchers, which can't be settled onchain
+
+    #[serde(default)]
+    pub request_binding: bool,
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PricingConfig {
+    #[serde_as(as = "DisplayFromStr")]
+    pub default: U256, // Per request, in the token's smallest unit
+
+    #[serde(default)]
+    pub routes: Vec<RoutePrice>, // First match wins, otherwise the default
+}
+
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutePrice {
+    pub path: String, // Exact path, or prefix ending with `*`
+
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub method: Option<Method>, // Any method if not set
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub price: U256,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub timestamp_window: u64,        // Seconds
+    pub rate_limit: u64,              // Requests per sender
+    pub rate_limit_window: u64,       // Seconds
+    pub idempotency_ttl: Option<u64>, // Seconds, idempotency keys are ignored if not set
+    pub cache_responses: bool, // Replay the cached response for retried idempotency keys, otherwise they get `409`
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            timestamp_window: 300,
+            rate_limit: 100,
+            rate_limit_window: 60,
+            idempotency_ttl: None,
+            cache_responses: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Memory, // Channel state is kept in memory, resynced from the chain on restart
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub audit_log: Option<PathBuf>, // JSONL audit log of the charges, if set
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SettlementConfig {
+    pub reconcile_interval: Option<u64>, // Seconds between reconciliations with the contracts, none if not set
+    pub quarantine: bool,                // Quarantine the channels found out of sync
+    pub prune_interval: Option<u64>, // Seconds between prunings of the stale channels, none if not set
+    pub retention: u64,              // Seconds the closed or expired channels are kept for
+    pub settle_interval: Option<u64>, // Seconds between checks for channels to close, never closed automatically if not set
+
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub settle_threshold: Option<U256>, // Close once the kept voucher pays out this much
+
+    pub settle_before_expiry: Option<u64>, // Seconds, close the channels expiring sooner
+}
+
+impl Default for SettlementConfig {
+    fn default() -> Self {
+        Self {
+            reconcile_interval: None,
+            quarantine: true,
+            prune_interval: None,
+            retention: 86400,
+            settle_interval: None,
+            settle_threshold: None,
+            settle_before_expiry: None,
+        }
+    }
+}
+
+// Indexing of the channel events, for the channels of the factory paying one of `network.recipients`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexingConfig {
+    pub start_block: u64,     // Block to start from when there is no checkpoint yet
+    pub confirmations: u64,   // Blocks are only processed once they are this deep
+    pub max_block_range: u64, // Max blocks per `eth_getLogs` call
+    pub poll_interval: u64,   // Seconds
+    pub checkpoint_dir: Option<PathBuf>, // One checkpoint per recipient, restarts from `start_block` if not set
+}
+
+impl Default for IndexingConfig {
+    fn default() -> Self {
+        Self {
+            start_block: 0,
+            confirmations: 5,
+            max_block_range: 1000,
+            poll_interval: 12,
+            checkpoint_dir: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminConfig {
+    pub token_env: String, // Environment variable holding the admin token, never the token itself
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl PipegateConfig {
+    pub fn from_toml(config: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(config).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    // The file at `PIPEGATE_CONFIG`, `pipegate.toml` by default, with the environment overrides
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_file(Self::path())?.with_env()
+    }
+
+    // Where `load` reads the config from
+    pub fn path() -> PathBuf {
+        env::var(CONFIG_PATH_VAR)
+            .unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string())
+            .into()
+    }
+
+    // Override the file with `PIPEGATE_LISTEN`, `PIPEGATE_RPC_URL`, `PIPEGATE_FACTORY` and `PIPEGATE_PRICE`
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        self.with_vars(|name| env::var(name).ok())
+    }
+
+    pub(crate) fn with_vars(
+        mut self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        if let Some(listen) = var("PIPEGATE_LISTEN") {
+            self.server.listen = listen
+                .parse()
+                .map_err(|_| ConfigError::invalid("PIPEGATE_LISTEN", "not a socket address"))?;
+        }
+        if let Some(rpc_url) = var("PIPEGATE_RPC_URL") {
+            self.network.rpc_url = rpc_url
+                .parse()
+                .map_err(|_| ConfigError::invalid("PIPEGATE_RPC_URL", "not a URL"))?;
+        }
+        if let Some(factory) = var("PIPEGATE_FACTORY") {
+            self.network.factory = Some(
+                factory
+                    .parse()
+                    .map_err(|_| ConfigError::invalid("PIPEGATE_FACTORY", "not an address"))?,
+            );
+        }
+        if let Some(price) = var("PIPEGATE_PRICE") {
+            self.pricing.default = price
+                .parse()
+                .map_err(|_| ConfigError::invalid("PIPEGATE_PRICE", "not an amount"))?;
+        }
+
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !matches!(self.network.rpc_url.scheme(), "http" | "https") {
+            return Err(ConfigError::invalid(
+                "network.rpc_url",
+                "must be an http(s) URL",
+            ));
+        }
+
+        // Free routes are set with `server.free_paths`, not a zero price
+        if self.pricing.default.is_zero() {
+            return Err(ConfigError::invalid("pricing.default", "must not be zero"));
+        }
+        for (i, route) in self.pricing.routes.iter().enumerate() {
+            if !route.path.starts_with('/') {
+                return Err(ConfigError::invalid(
+                    &format!("pricing.routes[{}].path", i),
+                    "must start with /",
+                ));
+            }
+            if route.price.is_zero() {
+                return Err(ConfigError::invalid(
+                    &format!("pricing.routes[{}].price", i),
+                    "must not be zero",
+                ));
+            }
+        }
+        for (i, path) in self.server.free_paths.iter().enumerate() {
+            if !path.starts_with('/') {
+                return Err(ConfigError::invalid(
+                    &format!("server.free_paths[{}]", i),
+                    "must start with /",
+                ));
+            }
+        }
+
+        if self.limits.timestamp_window == 0 {
+            return Err(ConfigError::invalid(
+                "limits.timestamp_window",
+                "must not be zero",
+            ));
+        }
+        if self.limits.rate_limit == 0 || self.limits.rate_limit_window == 0 {
+            return Err(ConfigError::invalid(
+                "limits.rate_limit",
+                "must not be zero",
+            ));
+        }
+        if self.limits.idempotency_ttl == Some(0) {
+            return Err(ConfigError::invalid(
+                "limits.idempotency_ttl",
+                "must not be zero",
+            ));
+        }
+
+        if self.settlement.reconcile_interval == Some(0) {
+            return Err(ConfigError::invalid(
+                "settlement.reconcile_interval",
+                "must not be zero",
+            ));
+        }
+        if self.settlement.prune_interval == Some(0) {
+            return Err(ConfigError::invalid(
+                "settlement.prune_interval",
+                "must not be zero",
+            ));
+        }
+        if let Some(interval) = self.settlement.settle_interval {
+            if interval == 0 {
+                return Err(ConfigError::invalid(
+                    "settlement.settle_interval",
+                    "must not be zero",
+                ));
+            }
+            if self.settlement.settle_threshold.is_none()
+                && self.settlement.settle_before_expiry.is_none()
+            {
+                return Err(ConfigError::invalid(
+                    "settlement.settle_interval",
+                    "needs settle_threshold or settle_before_expiry",
+                ));
+            }
+            // The server key closes the channels, it has to be their recipient
+            if self.server.signer_key_env.is_none() {
+                return Err(ConfigError::invalid(
+                    "settlement.settle_interval",
+                    "needs server.signer_key_env",
+                ));
+            }
+        }
+        if self.settlement.settle_threshold == Some(U256::ZERO) {
+            return Err(ConfigError::invalid(
+                "settlement.settle_threshold",
+                "must not be zero",
+            ));
+        }
+
+        if let Some(indexer) = &self.indexer {
+            if self.network.factory.is_none() {
+                return Err(ConfigError::invalid("indexer", "needs network.factory"));
+            }
+            if self.network.recipients.is_empty() {
+                return Err(ConfigError::invalid("indexer", "needs network.recipients"));
+            }
+            if indexer.poll_interval == 0 {
+                return Err(ConfigError::invalid(
+                    "indexer.poll_interval",
+                    "must not be zero",
+                ));
+            }
+            if indexer.max_block_range == 0 {
+                return Err(ConfigError::invalid(
+                    "indexer.max_block_range",
+                    "must not be zero",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn pricing(&self) -> Pricing {
+        Pricing {
+            default: self.pricing.default,
+            routes: self.pricing.routes.clone(),
+        }
+    }
+
+    // The part of the config that can be reloaded while the server runs
+    pub fn settings(&self) -> Settings {
+        let access = self
+            .server
+            .free_paths
+            .iter()
+            .fold(AccessPolicy::new(), |access, path| {
+                access.with_free_path(path.clone())
+            });
+        let access = self
+            .server
+            .allowed_senders
+            .iter()
+            .fold(access, |access, sender| access.with_allowed_sender(*sender));
+
+        Settings {
+            pricing: Some(self.pricing()),
+            access,
+            rate_limit: self.limits.rate_limit,
+            rate_limit_window: Duration::from_secs(self.limits.rate_limit_window),
+        }
+    }
+
+    // Set up the channel state, reading the server key and opening the audit log if configured
+    pub fn channel_state(&self) -> Result<ChannelState, ConfigError> {
+        let mut state = ChannelState::new(self.network.rpc_url.clone())
+            .with_settings(self.settings())
+            .with_browser_mode(self.server.browser_mode)
+            .with_legacy_messages(self.network.legacy_messages)
+            .with_typed_vouchers(self.network.typed_vouchers)
+            .with_request_binding(self.network.request_binding)
+            .with_recipients(self.network.recipients.clone())
+            .with_tokens(self.network.tokens.clone())
+            .with_timestamp_window(Duration::from_secs(self.limits.timestamp_window));
+
+        if let Some(factory) = self.network.factory {
+            state = state.with_factory(factory);
+        }
+        if let Some(ttl) = self.limits.idempotency_ttl {
+            state = state.with_idempotency(Duration::from_secs(ttl), self.limits.cache_responses);
+        }
+        if let Some(name) = &self.server.signer_key_env {
+            let signer: PrivateKeySigner = env::var(name)
+                .map_err(|_| {
+                    ConfigError::invalid("server.signer_key_env", format!("{} not set", name))
+                })?
+                .parse()
+                .map_err(|_| {
+                    ConfigError::invalid(
+                        "server.signer_key_env",
+                        format!("{} is not a private key", name),
+                    )
+                })?;
+            state = state.with_signer(signer);
+        }
+        if let Some(path) = &self.storage.audit_log {
+            state = state.with_audit_log(AuditLog::open(path)?);
+        }
+
+        Ok(state)
+    }
+
+    pub fn build(&self) -> Result<Pipegate, ConfigError> {
+        let admin_token = match &self.admin {
+            Some(admin) => Some(env::var(&admin.token_env).map_err(|_| {
+                ConfigError::invalid("admin.token_env", format!("{} not set", admin.token_env))
+            })?),
+            None => None,
+        };
+
+        Ok(Pipegate {
+            state: self.channel_state()?,
+            settlement: self.settlement.clone(),
+            indexers: self.indexers(),
+            admin_token,
+        })
+    }
+
+    // One indexer per recipient, all following the factory
+    pub fn indexers(&self) -> Vec<IndexerConfig> {
+        let (Some(indexer), Some(factory)) = (&self.indexer, self.network.factory) else {
+            return Vec::new();
+        };
+
+        self.network
+            .recipients
+            .iter()
+            .map(|recipient| {
+                let mut config = IndexerConfig::new(factory, *recipient, indexer.start_block);
+                config.confirmations = indexer.confirmations;
+                config.max_block_range = indexer.max_block_range;
+                config.poll_interval = Duration::from_secs(indexer.poll_interval);
+                config.checkpoint_path = indexer
+                    .checkpoint_dir
+                    .as_ref()
+                    .map(|dir| dir.join(format!("indexer-{}.json", recipient)));
+                config
+            })
+            .collect()
+    }
+}
+
+// Price of each route
+#[derive(Clone, Debug)]
+pub struct Pricing {
+    default: U256,
+    routes: Vec<RoutePrice>,
+}
+
+impl Pricing {
+    pub fn new(default: U256) -> Self {
+        Self {
+            default,
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn with_route(
+        mut self,
+        method: Option<Method>,
+        path: impl Into<String>,
+        price: U256,
+    ) -> Self {
+        self.routes.push(RoutePrice {
+            path: path.into(),
+            method,
+            price,
+        });
+        self
+    }
+
+    pub fn price(&self, method: &Method, path: &str) -> U256 {
+        self.routes
+            .iter()
+            .find(|route| {
+                route.method.as_ref().is_none_or(|m| m == method) && matches_path(&route.path, path)
+            })
+            .map(|route| route.price)
+            .unwrap_or(self.default)
+    }
+}
+
+// What can change while the server runs, swapped as a whole by `ChannelState::reload_settings`
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub pricing: Option<Pricing>, // Prices of the routes charged through `Pipegate`
+    pub access: AccessPolicy,
+    pub rate_limit: u64, // Requests per sender
+    pub rate_limit_window: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            pricing: None,
+            access: AccessPolicy::default(),
+            rate_limit: 100,
+            rate_limit_window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Settings {
+    // What the audit log records of a change
+    pub fn summary(&self) -> String {
+        let pricing = match &self.pricing {
+            Some(pricing) => format!(
+                "price {} with {} routes",
+                pricing.default,
+                pricing.routes.len()
+            ),
+            None => "no pricing".to_string(),
+        };
+
+        format!(
+            "{}, {} free paths, {} free methods, {} allowed senders, rate limit {} per {}s",
+            pricing,
+            self.access.free_paths().len(),
+            self.access.free_methods().len(),
+            self.access.allowed_senders().len(),
+            self.rate_limit,
+            self.rate_limit_window.as_secs()
+        )
+    }
+}
+
+type MiddlewareFuture = Pin<Box<dyn Future<Output = Result<Response, StatusCode>> + Send>>;
+type Middleware = fn(State<Pipegate>, Request, Next) -> MiddlewareFuture;
+
+// Middleware layer for a router, charging each route its price
+pub type PipegateLayer = FromFnLayer<Middleware, Pipegate, (State<Pipegate>, Request)>;
+
+// The middleware set up from a `PipegateConfig`
+#[derive(Clone)]
+pub struct Pipegate {
+    state: ChannelState,
+    settlement: SettlementConfig,
+    indexers: Vec<IndexerConfig>,
+    admin_token: Option<String>,
+}
+
+impl Pipegate {
+    pub fn new(state: ChannelState, pricing: Pricing) -> Self {
+        let settings = Settings {
+            pricing: Some(pricing),
+            ..(*state.settings()).clone()
+        };
+
+        Self {
+            state: state.with_settings(settings),
+            settlement: SettlementConfig::default(),
+            indexers: Vec::new(),
+            admin_token: None,
+        }
+    }
+
+    pub fn state(&self) -> &ChannelState {
+        &self.state
+    }
+
+    pub fn layer(&self) -> PipegateLayer {
+        axum::middleware::from_fn_with_state(self.clone(), charge as Middleware)
+    }
+
+    // The admin API if configured, reloading the settings from `path`. Mount it apart from the paid routes
+    pub fn admin_router(&self, path: impl Into<PathBuf>) -> Option<Router> {
+        let token = self.admin_token.as_ref()?;
+        Some(
+            Admin::new(self.state.clone(), token)
+                .with_reload(self.clone(), path)
+                .router(),
+        )
+    }
+
+    // Reload the pricing, allowlists and rate limits from the config file, returns the settings now in use
+    // Everything else in the file needs a restart
+    pub fn reload(
+        &self,
+        path: impl AsRef<Path>,
+        source: &str,
+    ) -> Result<Arc<Settings>, ConfigError> {
+        let config = PipegateConfig::from_file(path)?.with_env()?;
+        self.state.reload_settings(config.settings(), source);
+        Ok(self.state.settings())
+    }
+
+    // Reload from `path` on every SIGHUP
+    #[cfg(unix)]
+    pub fn spawn_reload_on_sighup(&self, path: PathBuf) -> JoinHandle<()> {
+        let pipegate = self.clone();
+        tokio::spawn(async move {
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    println!("Failed: SIGHUP handler - Error {}", e);
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                if let Err(e) = pipegate.reload(&path, "SIGHUP") {
+                    println!("Failed: Reload {} - Error {}", path.display(), e);
+                }
+            }
+        })
+    }
+
+    // Start the reconciler, the pruner and the settler, as set in the settlement config, and the indexers
+    pub fn spawn_tasks(&self) -> Vec<JoinHandle<()>> {
+        let mut tasks = Vec::new();
+        for config in &self.indexers {
+            tasks.push(ChannelIndexer::new(self.state.clone(), config.clone()).spawn());
+        }
+        if let Some(interval) = self.settlement.reconcile_interval {
+            tasks.push(spawn_reconciler(
+                self.state.clone(),
+                Duration::from_secs(interval),
+                self.settlement.quarantine,
+            ));
+        }
+        if let Some(interval) = self.settlement.prune_interval {
+            tasks.push(self.state.spawn_pruner(
+                Duration::from_secs(interval),
+                Duration::from_secs(self.settlement.retention),
+            ));
+        }
+        // Checked by `validate`, the server key is set along with the interval
+        if let (Some(interval), Some(signer)) =
+            (self.settlement.settle_interval, self.state.signer())
+        {
+            tasks.push(
+                self.state.spawn_settler(
+                    signer.clone(),
+                    Duration::from_secs(interval),
+                    self.settlement.settle_threshold,
+                    self.settlement
+                        .settle_before_expiry
+                        .map(Duration::from_secs),
+                ),
+            );
+        }
+        tasks
+    }
+}
+
+fn charge(State(pipegate): State<Pipegate>, request: Request, next: Next) -> MiddlewareFuture {
+    Box::pin(async move {
+        // Priced, and then checked, with the settings in use when the request came in, a reload doesn't affect it
+        let settings = pipegate.state.settings();
+        let price = settings
+            .pricing
+            .as_ref()
+            .map(|pricing| pricing.price(request.method(), request.uri().path()))
+            .unwrap_or_default();
+        middleware_with_settings(pipegate.state, settings, price, request, next).await
+    })
+}
@@ -0,0 +1,81 @@
+// Browser mode, for the dApps calling the API directly
+// Preflights are answered without payment, and every response lets the browser send and read the pipegate headers
+// Payments are authenticated by the signed headers rather than cookies, so any origin is allowed
+
+use axum::{
+    body::Body,
+    http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+            ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_TYPE,
+        },
+        HeaderMap, HeaderValue, Method, Request, StatusCode,
+    },
+    response::Response,
+};
+
+use crate::{access::INTERNAL_HEADER, idempotency::IDEMPOTENCY_HEADER, receipt::RECEIPT_HEADER};
+
+// Headers the clients send
+pub const REQUEST_HEADERS: &[&str] = &[
+    "X-Payment",
+    "X-Timestamp",
+    "X-Signature",
+    "X-Message",
+    "X-Message-Version",
+    "X-Signed-Headers",
+    "X-Channel-Id",
+    INTERNAL_HEADER,
+    IDEMPOTENCY_HEADER,
+];
+
+// Headers the clients read from the response
+pub const RESPONSE_HEADERS: &[&str] = &["X-Payment", "X-Timestamp", RECEIPT_HEADER];
+
+// How long browsers can cache a preflight, in seconds
+const PREFLIGHT_MAX_AGE: u64 = 600;
+
+pub fn is_preflight(request: &Request<Body>) -> bool {
+    request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+// Allow whatever method the browser asks for, along with the pipegate headers and the ones it asks for
+pub fn preflight_response(headers: &HeaderMap) -> Response {
+    let mut allowed_headers = vec![CONTENT_TYPE.as_str()];
+    allowed_headers.extend(REQUEST_HEADERS);
+    if let Some(requested) = headers
+        .get(ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|value| value.to_str().ok())
+    {
+        allowed_headers.extend(requested.split(',').map(str::trim));
+    }
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+
+    let response_headers = response.headers_mut();
+    if let Some(method) = headers.get(ACCESS_CONTROL_REQUEST_METHOD) {
+        response_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, method.clone());
+    }
+    if let Ok(allowed_headers) = HeaderValue::from_str(&allowed_headers.join(", ")) {
+        response_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+    }
+    response_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(PREFLIGHT_MAX_AGE));
+    insert_cors_headers(&mut response);
+
+    response
+}
+
+// Set on every response, errors included, so the browser can read the status and the channel state
+pub fn insert_cors_headers(response: &mut Response) {
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_str(&RESPONSE_HEADERS.join(", ")).unwrap(),
+    );
+}
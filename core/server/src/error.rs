@@ -1,6 +1,10 @@
+use std::io;
+
 use axum::http::StatusCode;
 use thiserror::Error;
 
+use crate::types::ChannelStatus;
+
 #[derive(Error, Debug)]
 pub enum AuthError {
     #[error("Missing required headers")]
@@ -17,6 +21,12 @@ pub enum AuthError {
     InvalidChannel,
     #[error("Channel not found")]
     ChannelNotFound,
+    #[error("Payment channel closed")]
+    ChannelClosed,
+    #[error("Payment channel not active")]
+    ChannelUnavailable,
+    #[error("Invalid channel status transition from {0:?} to {1:?}")]
+    InvalidTransition(ChannelStatus, ChannelStatus),
     #[error("Rate limit exceeded")]
     RateLimitExceeded,
     #[error("Contract interaction failed: {0}")]
@@ -27,6 +37,16 @@ pub enum AuthError {
     InvalidConfig,
     #[error("Invalid message")]
     InvalidMessage,
+    #[error("Idempotency key already used for another request")]
+    IdempotencyKeyReused,
+    #[error("Request with the same idempotency key in progress")]
+    RequestInProgress,
+    #[error("Request with the same idempotency key already served, its response wasn't kept")]
+    AlreadyServed,
+    #[error("Sender not allowed")]
+    SenderNotAllowed,
+    #[error("Voucher claims more than was served")]
+    ClaimExceedsServed,
 }
 
 impl From<AuthError> for StatusCode {
@@ -40,10 +60,49 @@ impl From<AuthError> for StatusCode {
             AuthError::InvalidChannel => StatusCode::BAD_REQUEST,
             AuthError::RateLimitExceeded => StatusCode::TOO_MANY_REQUESTS,
             AuthError::ChannelNotFound => StatusCode::NOT_FOUND,
+            AuthError::ChannelClosed => StatusCode::GONE,
+            AuthError::ChannelUnavailable => StatusCode::CONFLICT,
+            AuthError::InvalidTransition(_, _) => StatusCode::CONFLICT,
             AuthError::ContractError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AuthError::NetworkError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AuthError::InvalidConfig => StatusCode::BAD_REQUEST,
             AuthError::InvalidMessage => StatusCode::BAD_REQUEST,
+            AuthError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            AuthError::RequestInProgress => StatusCode::CONFLICT,
+            AuthError::AlreadyServed => StatusCode::CONFLICT,
+            AuthError::SenderNotAllowed => StatusCode::FORBIDDEN,
+            AuthError::ClaimExceedsServed => StatusCode::CONFLICT,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to parse config: {0}")]
+    Parse(String),
+    #[error("Invalid {field}: {reason}")]
+    Invalid { field: String, reason: String },
+}
+
+impl ConfigError {
+    pub(crate) fn invalid(field: &str, reason: impl Into<String>) -> Self {
+        ConfigError::Invalid {
+            field: field.to_string(),
+            reason: reason.into(),
         }
     }
 }
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Chain(#[from] AuthError),
+    #[error("{0}")]
+    Refused(String), // The command would fail onchain, nothing was sent
+    #[error("{0} check(s) failed")]
+    Doctor(usize),
+}
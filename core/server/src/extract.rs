@@ -0,0 +1,136 @@
+// Axum extractors for the paid handlers
+// `PaymentContext` for the handlers behind the middleware, `Paid` for the handlers charging on their own
+
+use std::marker::PhantomData;
+
+use alloy::primitives::{Address, U256};
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRef, FromRequest, FromRequestParts, Request},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    channel::ChannelState,
+    middleware::{parse_signed_request, payment_headers},
+    types::PaymentChannel,
+    verify::verify_and_update_channel,
+};
+
+// The verified payer of the request, inserted in the request extensions once the payment went through
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentContext {
+    pub sender: Address,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub nonce: U256, // Nonce of the voucher paying for the request
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: U256, // Charged for the request
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub balance: U256, // Left in the channel afterwards
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for PaymentContext {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Only missing if the handler isn't behind the middleware, use `Option<PaymentContext>` for routes that can be free
+        parts
+            .extensions
+            .get::<PaymentContext>()
+            .cloned()
+            .ok_or_else(|| {
+                println!("Failed: No payment context, is the route behind the middleware?");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+    }
+}
+
+// Price of a handler charging through `Paid`
+pub trait Price: Send + Sync + 'static {
+    fn amount() -> U256;
+}
+
+// Fixed price per request, `Paid<PerRequest<1000>>`
+pub struct PerRequest<const AMOUNT: u64>;
+
+impl<const AMOUNT: u64> Price for PerRequest<AMOUNT> {
+    fn amount() -> U256 {
+        U256::from(AMOUNT)
+    }
+}
+
+// Charges the request when the handler declares it, with the same verification as the middleware
+// The router state has to provide the `ChannelState`. The extractor consumes the body, it has to come last
+pub struct Paid<P: Price> {
+    pub payment: PaymentContext,
+    pub payment_channel: PaymentChannel, // Updated channel, to hand back to the client
+    pub body: Bytes,
+    timestamp: u64,
+    price: PhantomData<P>,
+}
+
+impl<P: Price> Paid<P> {
+    // Headers with the updated channel, the handler should return them along with its response
+    pub fn headers(&self) -> HeaderMap {
+        payment_headers(&self.payment_channel, self.timestamp)
+    }
+
+    // Deserialize the paid body
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+#[async_trait]
+impl<S, P> FromRequest<S> for Paid<P>
+where
+    S: Send + Sync,
+    P: Price,
+    ChannelState: FromRef<S>,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let state = ChannelState::from_ref(state);
+        let payment_amount = P::amount();
+
+        let (parts, body) = request.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.map_err(|_| {
+            println!("Failed: Body decode");
+            StatusCode::BAD_REQUEST
+        })?;
+
+        let (signed_request, timestamp) =
+            parse_signed_request(&parts, &body, payment_amount, state.timestamp_window())?;
+        let nonce = signed_request.payment_channel.nonce;
+
+        let payment_channel = verify_and_update_channel(&state, signed_request)
+            .await
+            .map_err(StatusCode::from)?;
+
+        Ok(Self {
+            payment: PaymentContext {
+                sender: payment_channel.sender,
+                channel_id: payment_channel.channel_id,
+                nonce,
+                amount: payment_amount,
+                balance: payment_channel.balance,
+            },
+            payment_channel,
+            body,
+            timestamp,
+            price: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,167 @@
+// Reverse proxy charging for any upstream HTTP service, whatever it's written in
+// Requests go through the middleware, then are forwarded upstream with the verified payer in trusted headers,
+// and the upstream response is streamed back. Run with the `gateway` binary, configured from a TOML file,
+// the `PipegateConfig` along with the upstream
+
+use std::{fs, path::Path};
+
+use alloy::transports::http::reqwest::Url;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::Response,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    channel::ChannelState,
+    config::{Pipegate, PipegateConfig},
+    cors::REQUEST_HEADERS,
+    error::ConfigError,
+    extract::PaymentContext,
+};
+
+// Set by the gateway on the forwarded requests, the upstream can trust them, whatever the client sent is dropped
+pub const SENDER_HEADER: &str = "X-Pipegate-Sender";
+pub const CHANNEL_HEADER: &str = "X-Pipegate-Channel-Id";
+
+// Only meaningful for one connection, not forwarded
+const HOP_BY_HOP_HEADERS: &[HeaderName] = &[
+    header::CONNECTION,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayConfig {
+    #[serde_as(as = "DisplayFromStr")]
+    pub upstream: Url, // Service the paid requests are forwarded to
+
+    #[serde(flatten)]
+    pub pipegate: PipegateConfig, // Listen address, network, pricing...
+}
+
+impl GatewayConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.pipegate.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Clone)]
+struct Gateway {
+    upstream: Url,
+    client: reqwest::Client,
+    state: ChannelState, // For the audit log
+}
+
+// Every route is forwarded, behind the middleware charging the price of the route
+pub fn gateway_router(pipegate: &Pipegate, upstream: Url) -> Router {
+    let gateway = Gateway {
+        upstream,
+        client: reqwest::Client::new(),
+        state: pipegate.state().clone(),
+    };
+
+    Router::new()
+        .fallback(forward)
+        .with_state(gateway)
+        .layer(pipegate.layer())
+}
+
+async fn forward(
+    State(gateway): State<Gateway>,
+    payment: Option<PaymentContext>,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    let (parts, body) = request.into_parts();
+
+    // Under the upstream path, if it has one
+    let mut url = gateway.upstream.clone();
+    let path = format!(
+        "{}{}",
+        gateway.upstream.path().trim_end_matches('/'),
+        parts.uri.path()
+    );
+    url.set_path(&path);
+    url.set_query(parts.uri.query());
+
+    let mut headers = forwarded_headers(&parts.headers);
+    headers.remove(header::HOST);
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(SENDER_HEADER);
+    headers.remove(CHANNEL_HEADER);
+    // The payment, the internal token and the idempotency key are for the gateway, the upstream could replay them
+    for name in REQUEST_HEADERS {
+        headers.remove(*name);
+    }
+    if let Some(payment) = &payment {
+        headers.insert(SENDER_HEADER, payment.sender.to_string().parse().unwrap());
+        headers.insert(
+            CHANNEL_HEADER,
+            payment.channel_id.to_string().parse().unwrap(),
+        );
+    }
+
+    // Already buffered by the middleware
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let route = format!("{} {}", parts.method, parts.uri.path());
+    let upstream_response = gateway
+        .client
+        .request(parts.method, url)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| {
+            println!("Failed: Upstream request - Error {}", e);
+            // The middleware charged before forwarding, the payment isn't refunded
+            if let Some(payment) = &payment {
+                gateway.state.record_change(format!(
+                    "upstream failed for {} charged to channel {} at nonce {} ({}): {}",
+                    route, payment.channel_id, payment.nonce, payment.amount, e
+                ));
+            }
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = upstream_response.status();
+    *response.headers_mut() = forwarded_headers(upstream_response.headers());
+    *response.body_mut() = Body::from_stream(upstream_response.bytes_stream());
+
+    Ok(response)
+}
+
+fn forwarded_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    // Along with the headers `Connection` lists as hop-by-hop for this connection
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+    headers
+}
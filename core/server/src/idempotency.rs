@@ -0,0 +1,165 @@
+// Idempotency keys, so that a client retrying after a lost response isn't charged twice
+// The key is sent in the `Idempotency-Key` header and must be signed with the canonical request ( listed in `X-Signed-Headers` ),
+// the outcome of the first request is kept for a while and returned to the retries without deducting again
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::{keccak256, FixedBytes, U256};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+};
+
+use crate::{
+    error::AuthError,
+    types::{PaymentChannel, SignedRequest},
+};
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+// Response of the first request, returned as is to the retries
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    // First time the key is seen, the key stays reserved for this request until the guard is completed or dropped
+    Fresh(Reservation),
+    // Retry of a request already served, with the channel state it was charged at
+    Replay {
+        payment_channel: PaymentChannel,
+        response: Option<Box<CachedResponse>>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    fingerprint: FixedBytes<32>, // Hash of the signed message and the signature
+    expires_at: Instant,
+    served: Option<(PaymentChannel, Option<CachedResponse>)>, // None while in flight
+}
+
+#[derive(Clone, Debug)]
+pub struct IdempotencyCache {
+    entries: Arc<Mutex<HashMap<(U256, String), Entry>>>, // Keyed by channel id and idempotency key
+    ttl: Duration,
+    cache_responses: bool, // Keep the whole response, otherwise retries are refused with `409`
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration, cache_responses: bool) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+            cache_responses,
+        }
+    }
+
+    pub fn caches_responses(&self) -> bool {
+        self.cache_responses
+    }
+
+    // Look the key up, and reserve it if it's new
+    pub fn begin(&self, key: &str, request: &SignedRequest) -> Result<Outcome, AuthError> {
+        let fingerprint =
+            keccak256([request.message.as_slice(), &request.signature.as_bytes()].concat());
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires_at > now);
+
+        let id = (request.payment_channel.channel_id, key.to_string());
+        match entries.get(&id) {
+            Some(entry) if entry.fingerprint != fingerprint => Err(AuthError::IdempotencyKeyReused),
+            Some(Entry { served: None, .. }) => Err(AuthError::RequestInProgress),
+            Some(Entry {
+                served: Some((payment_channel, response)),
+                ..
+            }) => Ok(Outcome::Replay {
+                payment_channel: payment_channel.clone(),
+                response: response.clone().map(Box::new),
+            }),
+            None => {
+                entries.insert(
+                    id.clone(),
+                    Entry {
+                        fingerprint,
+                        expires_at: now + self.ttl,
+                        served: None,
+                    },
+                );
+                Ok(Outcome::Fresh(Reservation {
+                    cache: self.clone(),
+                    id,
+                    charged: None,
+                }))
+            }
+        }
+    }
+}
+
+// Key reserved by a request in flight
+// Dropped before the charge it releases the key so the request can be retried, dropped after it ( say the client went away
+// while the handler ran ) it keeps the charge without a response, so the retries get `409` with the payment headers
+// rather than `RequestInProgress` until the key expires
+#[derive(Debug)]
+pub struct Reservation {
+    cache: IdempotencyCache,
+    id: (U256, String),
+    charged: Option<PaymentChannel>,
+}
+
+impl Reservation {
+    // The request was charged, at this channel state
+    pub fn charged(&mut self, payment_channel: PaymentChannel) {
+        self.charged = Some(payment_channel);
+    }
+
+    // Record the outcome of the request, for the retries
+    pub fn complete(mut self, response: Option<CachedResponse>) {
+        if let Some(payment_channel) = self.charged.take() {
+            self.cache.served(&self.id, payment_channel, response);
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        match self.charged.take() {
+            Some(payment_channel) => self.cache.served(&self.id, payment_channel, None),
+            None => {
+                // Not charged, or already completed
+                let mut entries = self.cache.entries.lock().unwrap();
+                if entries
+                    .get(&self.id)
+                    .is_some_and(|entry| entry.served.is_none())
+                {
+                    entries.remove(&self.id);
+                }
+            }
+        }
+    }
+}
+
+impl IdempotencyCache {
+    fn served(
+        &self,
+        id: &(U256, String),
+        payment_channel: PaymentChannel,
+        response: Option<CachedResponse>,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(id) {
+            entry.served = Some((payment_channel, response));
+            entry.expires_at = Instant::now() + self.ttl;
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// Chain event indexer
+// Polls the logs of the channel factory and of our payment channels, and keeps the local channel state in sync with what happened onchain
+// ( deposits, expiration extensions, closes and timeouts )
+
+use std::{collections::HashMap, fs, path::PathBuf, time::Duration};
+
+use alloy::{
+    primitives::{Address, FixedBytes, U256},
+    rpc::types::{Filter, Log},
+    sol_types::SolEvent,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    channel::{ChannelFactoryContract, ChannelState, PaymentChannelContract},
+    error::AuthError,
+    types::{ChannelEvent, ChannelRecord, ChannelStatus},
+};
+
+#[derive(Clone, Debug)]
+pub struct IndexerConfig {
+    pub factory: Address,     // ChannelFactory the channels are created from
+    pub recipient: Address,   // Our address, only channels paying us are indexed
+    pub start_block: u64,     // Block to start from when there is no checkpoint yet
+    pub confirmations: u64, // Blocks are only processed once they are this deep, and rescanned this far back after a reorg
+    pub max_block_range: u64, // Max blocks per `eth_getLogs` call
+    pub poll_interval: Duration,
+    pub checkpoint_path: Option<PathBuf>, // Where the checkpoint is persisted, if at all
+}
+
+impl IndexerConfig {
+    pub fn new(factory: Address, recipient: Address, start_block: u64) -> Self {
+        Self {
+            factory,
+            recipient,
+            start_block,
+            confirmations: 5,
+            max_block_range: 1000,
+            poll_interval: Duration::from_secs(12),
+            checkpoint_path: None,
+        }
+    }
+}
+
+// Last block the indexer has fully processed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub block_number: u64,
+    pub block_hash: FixedBytes<32>,
+}
+
+// What applying an event changed, to undo it if its block is reorged out
+#[derive(Clone, Debug)]
+enum Undo {
+    Deposit { credit: U256 },
+    Expiration { previous: U256 },
+    Status { previous: ChannelStatus },
+}
+
+#[derive(Clone, Debug)]
+struct Applied {
+    block_number: u64,
+    block_hash: FixedBytes<32>,
+    channel_id: U256,
+    undo: Undo,
+}
+
+pub struct ChannelIndexer {
+    state: ChannelState,
+    config: IndexerConfig,
+    checkpoint: Option<Checkpoint>,
+    channel_addresses: HashMap<Address, U256>, // Channel contracts created for us, learnt from the factory
+    applied: Vec<Applied>, // Events applied from the blocks a reorg could still rescan, oldest first
+}
+
+impl ChannelIndexer {
+    pub fn new(state: ChannelState, config: IndexerConfig) -> Self {
+        // Resume from the persisted checkpoint if there is one
+        let checkpoint = config
+            .checkpoint_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|data| serde_json::from_str(&data).ok());
+
+        Self {
+            state,
+            config,
+            checkpoint,
+            channel_addresses: HashMap::new(),
+            applied: Vec::new(),
+        }
+    }
+
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    pub fn checkpoint(&self) -> Option<&Checkpoint> {
+        self.checkpoint.as_ref()
+    }
+
+    // Run the indexer in the background, polling every `poll_interval`
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.poll().await {
+                    Ok(0) => {}
+                    Ok(applied) => println!("Indexer applied {} events", applied),
+                    Err(e) => println!("Indexer poll failed: {}", e),
+                }
+                tokio::time::sleep(self.config.poll_interval).await;
+            }
+        })
+    }
+
+    // Process the next range of confirmed blocks, returns the number of events that changed the local state
+    pub async fn poll(&mut self) -> Result<usize, AuthError> {
+        let backend = self.state.backend.clone();
+
+        let latest = backend.block_number().await?;
+        let safe = latest.saturating_sub(self.config.confirmations);
+
+        let from = match &self.checkpoint {
+            Some(checkpoint) => {
+                let hash = backend.block_hash(checkpoint.block_number).await?;
+                if hash != checkpoint.block_hash {
+                    // The checkpoint block was reorged out, rescan the blocks that might have changed
+                    // after undoing what their events did, the ones still on the chain are applied again
+                    println!(
+                        "Reorg detected at block {}, rescanning",
+                        checkpoint.block_number
+                    );
+                    let from = checkpoint
+                        .block_number
+                        .saturating_sub(self.config.confirmations)
+                        .max(self.config.start_block);
+                    let reverted = self.revert_from(from).await?;
+                    if reverted > 0 {
+                        println!("Reverted {} events from the orphaned blocks", reverted);
+                    }
+                    from
+                } else {
+                    checkpoint.block_number + 1
+                }
+            }
+            None => self.config.start_block,
+        };
+
+        if from > safe {
+            return Ok(0);
+        }
+        let to = safe.min(from + self.config.max_block_range.max(1) - 1);
+
+        // Learn about the channels created for us in this range
+        let factory_filter = Filter::new()
+            .address(self.config.factory)
+            .event_signature(ChannelFactoryContract::channelCreated::SIGNATURE_HASH)
+            .topic3(self.config.recipient.into_word())
+            .from_block(from)
+            .to_block(to);
+
+        for log in backend.get_logs(&factory_filter).await? {
+            if let Ok(created) =
+                ChannelFactoryContract::channelCreated::decode_log_data(log.data(), true)
+            {
+                self.channel_addresses
+                    .insert(created.channelAddress, created.channelId);
+            }
+        }
+
+        // Watch every channel we know of, either from the factory or already in use
+        let mut addresses: Vec<Address> = self.channel_addresses.keys().copied().collect();
+        for record in self.state.channels.read().await.values() {
+            if !addresses.contains(&record.channel.address) {
+                addresses.push(record.channel.address);
+            }
+        }
+
+        let mut applied = 0;
+        if !addresses.is_empty() {
+            let channel_filter = Filter::new()
+                .address(addresses)
+                .event_signature(vec![
+                    PaymentChannelContract::depositMade::SIGNATURE_HASH,
+                    PaymentChannelContract::expirationExtended::SIGNATURE_HASH,
+                    PaymentChannelContract::channelClosed::SIGNATURE_HASH,
+                    PaymentChannelContract::timeoutClaimed::SIGNATURE_HASH,
+                ])
+                .from_block(from)
+                .to_block(to);
+
+            let mut logs = backend.get_logs(&channel_filter).await?;
+            logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+            for log in logs {
+                if let Some(event) = decode_channel_event(&log) {
+                    let before = self.state.get_record(event.channel_id()).await;
+                    if self.state.apply_event(&event).await {
+                        applied += 1;
+                        if let Some(before) = before {
+                            let block_number = log.block_number.unwrap_or(to);
+                            let block_hash = match log.block_hash {
+                                Some(hash) => hash,
+                                None => backend.block_hash(block_number).await?,
+                            };
+                            self.applied.push(Applied {
+                                block_number,
+                                block_hash,
+                                channel_id: event.channel_id(),
+                                undo: undo_for(&event, &before),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        self.checkpoint = Some(Checkpoint {
+            block_number: to,
+            block_hash: backend.block_hash(to).await?,
+        });
+        self.save_checkpoint();
+
+        // Only the blocks within `confirmations` of the checkpoint are rescanned
+        let horizon = to.saturating_sub(self.config.confirmations);
+        self.applied
+            .retain(|applied| applied.block_number >= horizon);
+
+        Ok(applied)
+    }
+
+    // Undo the events applied from the blocks replaced since, from `from` on, newest first
+    // Returns the number of events reverted
+    async fn revert_from(&mut self, from: u64) -> Result<usize, AuthError> {
+        let backend = self.state.backend.clone();
+
+        // Nothing is dropped from the journal until every hash was read
+        let mut replaced = Vec::new();
+        for applied in &self.applied {
+            replaced.push(
+                applied.block_number >= from
+                    && backend.block_hash(applied.block_number).await? != applied.block_hash,
+            );
+        }
+        let (orphaned, kept): (Vec<_>, Vec<_>) = self
+            .applied
+            .drain(..)
+            .zip(replaced)
+            .partition(|(_, replaced)| *replaced);
+        self.applied = kept.into_iter().map(|(applied, _)| applied).collect();
+        let orphaned: Vec<Applied> = orphaned.into_iter().map(|(applied, _)| applied).collect();
+
+        let mut channels = self.state.channels.write().await;
+        for applied in orphaned.iter().rev() {
+            let Some(record) = channels.get_mut(&applied.channel_id) else {
+                continue;
+            };
+            match applied.undo {
+                // What was served against it stays served, the reconciler flags the channel if that's now more than the deposit
+                Undo::Deposit { credit } => {
+                    record.deposited = record.deposited.saturating_sub(credit);
+                    record.channel.balance = record.channel.balance.saturating_sub(credit);
+                }
+                Undo::Expiration { previous } => record.channel.expiration = previous,
+                // Set as is, the close never happened on this chain
+                Undo::Status { previous } => record.status = previous,
+            }
+            println!(
+                "Channel {} reverted: {:?}",
+                applied.channel_id, applied.undo
+            );
+        }
+
+        Ok(orphaned.len())
+    }
+
+    fn save_checkpoint(&self) {
+        if let (Some(path), Some(checkpoint)) = (&self.config.checkpoint_path, &self.checkpoint) {
+            if let Err(e) = fs::write(path, serde_json::to_string(checkpoint).unwrap()) {
+                println!("Failed: Checkpoint write - Error {}", e);
+            }
+        }
+    }
+}
+
+fn undo_for(event: &ChannelEvent, before: &ChannelRecord) -> Undo {
+    match event {
+        ChannelEvent::Deposit { new_balance, .. } => Undo::Deposit {
+            credit: new_balance.saturating_sub(before.deposited),
+        },
+        ChannelEvent::ExpirationExtended { .. } => Undo::Expiration {
+            previous: before.channel.expiration,
+        },
+        ChannelEvent::Closed { .. } | ChannelEvent::TimeoutClaimed { .. } => Undo::Status {
+            previous: before.status,
+        },
+    }
+}
+
+// Decode a payment channel log into the event it represents
+pub fn decode_channel_event(log: &Log) -> Option<ChannelEvent> {
+    let address = log.address();
+    let topic = *log.topic0()?;
+
+    match topic {
+        PaymentChannelContract::depositMade::SIGNATURE_HASH => {
+            let event =
+                PaymentChannelContract::depositMade::decode_log_data(log.data(), true).ok()?;
+            Some(ChannelEvent::Deposit {
+                channel_id: event.channel_id,
+                address,
+                amount: event.amount,
+                new_balance: event.newBalance,
+            })
+        }
+        PaymentChannelContract::expirationExtended::SIGNATURE_HASH => {
+            let event =
+                PaymentChannelContract::expirationExtended::decode_log_data(log.data(), true)
+                    .ok()?;
+            Some(ChannelEvent::ExpirationExtended {
+                channel_id: event.channel_id,
+                address,
+                expiration: event.expiration,
+            })
+        }
+        PaymentChannelContract::channelClosed::SIGNATURE_HASH => {
+            let event =
+                PaymentChannelContract::channelClosed::decode_log_data(log.data(), true).ok()?;
+            Some(ChannelEvent::Closed {
+                channel_id: event.channel_id,
+                address,
+                amount: event.amount,
+                nonce: event.nonce,
+            })
+        }
+        PaymentChannelContract::timeoutClaimed::SIGNATURE_HASH => {
+            let event =
+                PaymentChannelContract::timeoutClaimed::decode_log_data(log.data(), true).ok()?;
+            Some(ChannelEvent::TimeoutClaimed {
+                channel_id: event.channel_id,
+                address,
+            })
+        }
+        _ => None,
+    }
+}
@@ -1,16 +1,1263 @@
+pub mod access;
+pub mod admin;
+pub mod audit;
+pub mod chain;
 pub mod channel;
+pub mod cli;
+pub mod config;
+pub mod cors;
 pub mod error;
+pub mod extract;
+pub mod gateway;
+pub mod idempotency;
+pub mod indexer;
 pub mod middleware;
+pub mod receipt;
+pub mod reconcile;
+pub mod resync;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
 pub mod types;
 pub mod utils;
 pub mod verify;
+pub mod voucher;
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
 
-    // TODO: Implement the tests
+    use alloy::{
+        primitives::{Address, Bytes, U256},
+        signers::{local::PrivateKeySigner, Signature, SignerSync},
+    };
+    use axum::http::Method;
+
+    use crate::{
+        access::{sign_internal_token, verify_internal_request, AccessPolicy},
+        audit::{read_jsonl, verify_entries, AuditEntry, AuditLog, AuditViolation, Charge},
+        chain::MockBackend,
+        channel::{ChannelState, PaymentChannelContract},
+        config::{PipegateConfig, Settings},
+        error::{AuthError, ConfigError},
+        indexer::{ChannelIndexer, IndexerConfig},
+        reconcile::{reconcile_channels, Discrepancy},
+        resync::{create_resync_message, resync_channel},
+        types::{
+            CanonicalRequest, ChannelAdjustment, ChannelEvent, ChannelInfo, ChannelStatus,
+            MessageScheme, PaymentChannel, SignedRequest, NONCE_WINDOW,
+        },
+        utils::{create_domain_message, create_message},
+        verify::{verify_and_update_channel, verify_with_settings},
+        voucher::{create_typed_message, sign_voucher},
+    };
+
+    const PRICE: u64 = 1000;
+    const DEPOSIT: u64 = 1_000_000;
+
+    fn setup() -> (ChannelState, MockBackend, PrivateKeySigner, PaymentChannel) {
+        let backend = MockBackend::new();
+        let signer = PrivateKeySigner::random();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let channel = PaymentChannel {
+            address: Address::repeat_byte(1),
+            sender: signer.address(),
+            recipient: Address::repeat_byte(2),
+            balance: U256::from(DEPOSIT),
+            nonce: U256::ZERO,
+            expiration: U256::from(now + 3600),
+            channel_id: U256::from(1),
+        };
+
+        backend.set_channel(ChannelInfo {
+            address: channel.address,
+            sender: channel.sender,
+            recipient: channel.recipient,
+            balance: channel.balance,
+            expiration: channel.expiration,
+            channel_id: channel.channel_id,
+            price: U256::from(PRICE),
+            token: Address::repeat_byte(3),
+        });
+
+        let state = ChannelState::with_backend(Arc::new(backend.clone()));
+        (state, backend, signer, channel)
+    }
+
+    fn sign(signer: &PrivateKeySigner, channel: &PaymentChannel, body: &[u8]) -> SignedRequest {
+        let message = create_message(channel.channel_id, channel.balance, channel.nonce, body);
+        let signature = signer.sign_message_sync(&message).unwrap();
+        let signature = Signature::try_from(signature.as_bytes().as_slice()).unwrap();
+
+        SignedRequest {
+            scheme: MessageScheme::Legacy,
+            message,
+            signature,
+            payment_channel: channel.clone(),
+            payment_amount: U256::from(PRICE),
+            body_bytes: body.to_vec(),
+            request: None,
+            route: None,
+        }
+    }
+
+    // The channel as the client would send it for the next request, after reading the response
+    fn next(channel: &PaymentChannel) -> PaymentChannel {
+        PaymentChannel {
+            nonce: channel.nonce + U256::from(1),
+            ..channel.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn first_request_validates_and_charges() {
+        let (state, _, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b"{}"))
+            .await
+            .unwrap();
+
+        assert_eq!(updated.balance, U256::from(DEPOSIT - PRICE));
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Active)
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_need_increasing_nonces() {
+        let (state, _, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+        let updated = verify_and_update_channel(&state, sign(&signer, &next(&updated), b""))
+            .await
+            .unwrap();
+        assert_eq!(updated.balance, U256::from(DEPOSIT - 2 * PRICE));
+
+        let replay = verify_and_update_channel(&state, sign(&signer, &updated, b"")).await;
+        assert!(matches!(replay, Err(AuthError::InvalidNonce)));
+    }
+
+    // The channel as the client would send it for the k-th of a batch of requests fired at once
+    fn nth(channel: &PaymentChannel, k: u64) -> PaymentChannel {
+        PaymentChannel {
+            balance: channel.balance - U256::from(k * PRICE),
+            nonce: channel.nonce + U256::from(k),
+            ..channel.clone()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn serves_concurrent_requests_on_one_channel() {
+        let (state, _, signer, channel) = setup();
+        const REQUESTS: u64 = 64;
+
+        // Fired at once on a channel the server hasn't seen yet, arriving in any order
+        let tasks: Vec<_> = (0..REQUESTS)
+            .rev()
+            .map(|k| {
+                let state = state.clone();
+                let request = sign(&signer, &nth(&channel, k), b"{}");
+                tokio::spawn(async move { verify_and_update_channel(&state, request).await })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let record = state.channels.read().await[&channel.channel_id].clone();
+        assert_eq!(record.served, U256::from(REQUESTS * PRICE));
+        assert_eq!(
+            record.channel.balance,
+            U256::from(DEPOSIT - REQUESTS * PRICE)
+        );
+        assert_eq!(record.channel.nonce, U256::from(REQUESTS - 1));
+        assert_eq!(
+            record.lowest_claim,
+            U256::from(DEPOSIT - (REQUESTS - 1) * PRICE)
+        );
+
+        // Every nonce of the batch is spent
+        for k in [0, REQUESTS / 2, REQUESTS - 1] {
+            let replay =
+                verify_and_update_channel(&state, sign(&signer, &nth(&channel, k), b"{}")).await;
+            assert!(matches!(replay, Err(AuthError::InvalidNonce)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_vouchers_must_cover_what_was_served() {
+        let (state, _, signer, channel) = setup();
+        verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        // Fresh nonces, but all claiming the same balance, only one of them is paid for
+        let tasks: Vec<_> = (1..=16)
+            .map(|k| {
+                let state = state.clone();
+                let request = sign(
+                    &signer,
+                    &PaymentChannel {
+                        nonce: U256::from(k),
+                        balance: U256::from(DEPOSIT - PRICE),
+                        ..channel.clone()
+                    },
+                    b"",
+                );
+                tokio::spawn(async move { verify_and_update_channel(&state, request).await })
+            })
+            .collect();
+
+        let mut accepted = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(_) => accepted += 1,
+                Err(e) => assert!(matches!(e, AuthError::InsufficientBalance)),
+            }
+        }
+        assert_eq!(accepted, 1);
+    }
+
+    #[tokio::test]
+    async fn nonces_older_than_the_window_are_refused() {
+        let (state, _, signer, channel) = setup();
+
+        let first = NONCE_WINDOW + 10;
+        let result =
+            verify_and_update_channel(&state, sign(&signer, &nth(&channel, first), b"")).await;
+        assert!(matches!(result, Err(AuthError::InvalidNonce)));
+
+        verify_and_update_channel(&state, sign(&signer, &nth(&channel, 10), b""))
+            .await
+            .unwrap();
+        verify_and_update_channel(&state, sign(&signer, &nth(&channel, first), b""))
+            .await
+            .unwrap();
+
+        // Unused, but fell out of the window
+        let result = verify_and_update_channel(&state, sign(&signer, &nth(&channel, 5), b"")).await;
+        assert!(matches!(result, Err(AuthError::InvalidNonce)));
+        verify_and_update_channel(&state, sign(&signer, &nth(&channel, first - 1), b""))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_foreign_signature_and_tampered_message() {
+        let (state, _, signer, channel) = setup();
+
+        let other = PrivateKeySigner::random();
+        let result = verify_and_update_channel(&state, sign(&other, &channel, b"")).await;
+        assert!(matches!(result, Err(AuthError::InvalidSignature)));
+
+        let mut request = sign(&signer, &channel, b"{}");
+        request.body_bytes = b"{\"tampered\":true}".to_vec();
+        let result = verify_and_update_channel(&state, request).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+    }
+
+    #[tokio::test]
+    async fn accepts_typed_vouchers_for_the_right_chain() {
+        let (state, backend, signer, channel) = setup();
+        backend.set_chain_id(84532);
+
+        let typed = |chain_id: u64, channel: &PaymentChannel| SignedRequest {
+            scheme: MessageScheme::Eip712,
+            message: create_typed_message(chain_id, channel, b"{}"),
+            signature: sign_voucher(&signer, chain_id, channel, b"{}").unwrap(),
+            ..sign(&signer, channel, b"{}")
+        };
+
+        // Not unless enabled, they can't be settled
+        let result = verify_and_update_channel(&state, typed(84532, &channel)).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+        let state = state.with_typed_vouchers(true);
+
+        // Signed for another chain
+        let result = verify_and_update_channel(&state, typed(1, &channel)).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+
+        // A legacy signature doesn't pass as a typed one
+        let mut request = typed(84532, &channel);
+        request.signature = sign(&signer, &channel, b"{}").signature;
+        let result = verify_and_update_channel(&state, request).await;
+        assert!(matches!(result, Err(AuthError::InvalidSignature)));
+
+        let updated = verify_and_update_channel(&state, typed(84532, &channel))
+            .await
+            .unwrap();
+        assert_eq!(updated.balance, U256::from(DEPOSIT - PRICE));
+        let record = state.get_record(channel.channel_id).await.unwrap();
+        assert!(record.voucher.is_none());
+
+        // Both schemes can be used on the same channel, only the settleable voucher is kept
+        let updated = verify_and_update_channel(&state, sign(&signer, &next(&updated), b"{}"))
+            .await
+            .unwrap();
+        verify_and_update_channel(&state, typed(84532, &next(&updated)))
+            .await
+            .unwrap();
+        let voucher = state
+            .get_record(channel.channel_id)
+            .await
+            .unwrap()
+            .voucher
+            .unwrap();
+        assert_eq!(voucher.scheme, MessageScheme::Legacy);
+        assert_eq!(voucher.payment_channel.nonce, U256::from(1));
+    }
+
+    #[tokio::test]
+    async fn domain_messages_bind_chain_and_stay_settleable() {
+        let (state, backend, signer, channel) = setup();
+        backend.register_channel(Address::repeat_byte(9), channel.channel_id, channel.address);
+
+        // Without a factory there is no deployment to bind to, not even the zero address
+        let unbound = state.clone();
+        let message = create_domain_message(31337, Address::ZERO, &channel, b"{}");
+        let signature = signer.sign_message_sync(&message).unwrap();
+        let request = SignedRequest {
+            scheme: MessageScheme::Domain,
+            message,
+            signature: Signature::try_from(signature.as_bytes().as_slice()).unwrap(),
+            ..sign(&signer, &channel, b"{}")
+        };
+        let result = verify_and_update_channel(&unbound, request).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+        let result = unbound
+            .settlement_body(MessageScheme::Domain, &channel, b"{}")
+            .await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+
+        let state = state
+            .with_factory(Address::repeat_byte(9))
+            .with_legacy_messages(false);
+
+        let domain = |chain_id: u64, channel: &PaymentChannel| {
+            let message = create_domain_message(chain_id, Address::repeat_byte(9), channel, b"{}");
+            let signature = signer.sign_message_sync(&message).unwrap();
+            SignedRequest {
+                scheme: MessageScheme::Domain,
+                message,
+                signature: Signature::try_from(signature.as_bytes().as_slice()).unwrap(),
+                ..sign(&signer, channel, b"{}")
+            }
+        };
+
+        // Legacy messages are turned off
+        let result = verify_and_update_channel(&state, sign(&signer, &channel, b"{}")).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+
+        // Signed for another chain
+        let result = verify_and_update_channel(&state, domain(1, &channel)).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+
+        let request = domain(31337, &channel);
+        let signature = request.signature;
+        verify_and_update_channel(&state, request).await.unwrap();
+
+        // What the channel contract checks on close, with the domain body as raw body
+        let raw_body = state
+            .settlement_body(MessageScheme::Domain, &channel, b"{}")
+            .await
+            .unwrap();
+        let message = create_message(
+            channel.channel_id,
+            channel.balance,
+            channel.nonce,
+            &raw_body,
+        );
+        assert_eq!(
+            signature.recover_address_from_msg(message).unwrap(),
+            signer.address()
+        );
+    }
 
     #[test]
-    fn it_works() {}
+    fn canonical_request_is_normalized() {
+        let request = CanonicalRequest::new(
+            "get",
+            "/weather",
+            "units=metric&city=bangkok&city=amsterdam&",
+            vec![("Host ".to_string(), " api.example.com".to_string())],
+            1700000000,
+        );
+
+        assert_eq!(
+            request.encode(b"{}"),
+            b"GET\n/weather\ncity=amsterdam&city=bangkok&units=metric\nhost:api.example.com\n1700000000\n{}"
+        );
+    }
+
+    #[tokio::test]
+    async fn request_bound_vouchers_only_cover_their_endpoint() {
+        let (state, _, signer, channel) = setup();
+        let state = state.with_request_binding(true);
+
+        let bound = |path: &str, channel: &PaymentChannel| {
+            let canonical = CanonicalRequest::new("GET", path, "", vec![], 1700000000);
+            let mut request = sign(&signer, channel, &canonical.encode(b""));
+            request.body_bytes = vec![];
+            request.request = Some(canonical);
+            request
+        };
+
+        // Unbound vouchers are refused
+        let result = verify_and_update_channel(&state, sign(&signer, &channel, b"")).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+
+        // Replayed against another endpoint
+        let mut replayed = bound("/a", &channel);
+        replayed.request = bound("/b", &channel).request;
+        let result = verify_and_update_channel(&state, replayed).await;
+        assert!(matches!(result, Err(AuthError::InvalidMessage)));
+
+        verify_and_update_channel(&state, bound("/a", &channel))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn audit_log_chains_charges_and_detects_tampering() {
+        let (state, _, signer, channel) = setup();
+        let state = state.with_audit_log(AuditLog::new());
+
+        let mut updated = channel.clone();
+        for k in 0..3 {
+            let mut request = sign(&signer, &nth(&channel, k), b"");
+            request.route = Some("GET /weather".to_string());
+            updated = verify_and_update_channel(&state, request).await.unwrap();
+        }
+        // Refused requests aren't charged, so not logged
+        let _ = verify_and_update_channel(&state, sign(&signer, &updated, b"")).await;
+
+        let audit_log = state.audit_log().unwrap();
+        let entries = audit_log.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].nonce, U256::from(2));
+        assert_eq!(entries[2].route, "GET /weather");
+        assert_eq!(audit_log.verify(), Ok(()));
+
+        // Exported and read back
+        let mut exported = Vec::new();
+        audit_log.export_jsonl(&mut exported).unwrap();
+        let read = read_jsonl(exported.as_slice()).unwrap();
+        assert_eq!(read, entries);
+
+        let mut csv = Vec::new();
+        audit_log.export_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().lines().count(), 4);
+
+        // Persisted, and resumed from the file
+        let path = std::env::temp_dir().join(format!("pipegate-audit-{}.jsonl", signer.address()));
+        let persisted = AuditLog::open(&path).unwrap();
+        let charge = |entry: &AuditEntry| Charge {
+            channel_id: entry.channel_id,
+            sender: entry.sender,
+            nonce: entry.nonce,
+            amount: entry.amount,
+            route: Some(entry.route.clone()),
+            signature: entry.signature,
+        };
+        persisted.append(charge(&entries[0])).unwrap();
+        let resumed = AuditLog::open(&path).unwrap();
+        resumed.append(charge(&entries[1])).unwrap();
+        assert_eq!(AuditLog::open(&path).unwrap().entries().len(), 2);
+        assert_eq!(AuditLog::open(&path).unwrap().verify(), Ok(()));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut edited = entries.clone();
+        edited[1].amount = U256::ZERO;
+        assert_eq!(
+            verify_entries(&edited),
+            Err(AuditViolation::Edited { index: 1 })
+        );
+
+        let mut removed = entries.clone();
+        removed.remove(1);
+        assert_eq!(
+            verify_entries(&removed),
+            Err(AuditViolation::Gap {
+                expected: 1,
+                found: 2
+            })
+        );
+
+        // Rehashed after the edit, the next entry no longer points to it
+        edited[1].hash = edited[1].compute_hash();
+        assert_eq!(
+            verify_entries(&edited),
+            Err(AuditViolation::BrokenChain { index: 2 })
+        );
+
+        // The first line dropped from the export, what's left is still chained
+        let truncated: Vec<u8> = exported
+            .split_inclusive(|byte| *byte == b'\n')
+            .skip(1)
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(
+            verify_entries(&read_jsonl(truncated.as_slice()).unwrap()),
+            Err(AuditViolation::Gap {
+                expected: 0,
+                found: 1
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_balance_above_contract() {
+        let (state, _, signer, mut channel) = setup();
+        channel.balance = U256::from(2 * DEPOSIT);
+
+        let result = verify_and_update_channel(&state, sign(&signer, &channel, b"")).await;
+        assert!(matches!(result, Err(AuthError::InsufficientBalance)));
+        assert!(state.get_channel(channel.channel_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_channel_unknown_to_factory() {
+        let (state, backend, signer, channel) = setup();
+        let factory = Address::repeat_byte(9);
+        let state = state.with_factory(factory);
+
+        let result = verify_and_update_channel(&state, sign(&signer, &channel, b"")).await;
+        assert!(matches!(result, Err(AuthError::InvalidChannel)));
+
+        backend.register_channel(factory, channel.channel_id, channel.address);
+        let result = verify_and_update_channel(&state, sign(&signer, &channel, b"")).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn accepts_top_up_once_deposited_onchain() {
+        let (state, backend, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        // Claiming a top-up that didn't happen
+        let mut topped_up = next(&updated);
+        topped_up.balance += U256::from(DEPOSIT);
+        let result = verify_and_update_channel(&state, sign(&signer, &topped_up, b"")).await;
+        assert!(matches!(result, Err(AuthError::InsufficientBalance)));
+
+        let mut info = backend.channel(channel.address).unwrap();
+        info.balance += U256::from(DEPOSIT);
+        backend.set_channel(info);
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &topped_up, b""))
+            .await
+            .unwrap();
+        assert_eq!(updated.balance, U256::from(2 * DEPOSIT - 2 * PRICE));
+    }
+
+    #[tokio::test]
+    async fn top_up_checks_dont_block_other_channels() {
+        let (state, backend, signer, channel) = setup();
+
+        let other = PaymentChannel {
+            address: Address::repeat_byte(4),
+            channel_id: U256::from(2),
+            ..channel.clone()
+        };
+        let mut info = backend.channel(channel.address).unwrap();
+        backend.set_channel(ChannelInfo {
+            address: other.address,
+            channel_id: other.channel_id,
+            ..info.clone()
+        });
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+        let other = verify_and_update_channel(&state, sign(&signer, &other, b""))
+            .await
+            .unwrap();
+
+        // A top-up checked against a slow RPC
+        info.balance += U256::from(DEPOSIT);
+        backend.set_channel(info);
+        backend.set_read_delay(Duration::from_millis(500));
+        let mut topped_up = next(&updated);
+        topped_up.balance += U256::from(DEPOSIT);
+        let top_up = tokio::spawn({
+            let state = state.clone();
+            let request = sign(&signer, &topped_up, b"");
+            async move { verify_and_update_channel(&state, request).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The other channel is served meanwhile
+        let result = tokio::time::timeout(
+            Duration::from_millis(200),
+            verify_and_update_channel(&state, sign(&signer, &next(&other), b"")),
+        )
+        .await;
+        assert!(matches!(result, Ok(Ok(_))));
+
+        let updated = top_up.await.unwrap().unwrap();
+        assert_eq!(updated.balance, U256::from(2 * DEPOSIT - 2 * PRICE));
+    }
+
+    #[tokio::test]
+    async fn accepts_extension_once_extended_onchain() {
+        let (state, backend, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        let mut extended = next(&updated);
+        extended.expiration += U256::from(3600);
+        let result = verify_and_update_channel(&state, sign(&signer, &extended, b"")).await;
+        assert!(matches!(result, Err(AuthError::Expired)));
+
+        let mut info = backend.channel(channel.address).unwrap();
+        info.expiration = extended.expiration;
+        backend.set_channel(info);
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &extended, b""))
+            .await
+            .unwrap();
+        assert_eq!(updated.expiration, extended.expiration);
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_after_close() {
+        let (state, _, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        assert!(
+            state
+                .apply_event(&ChannelEvent::Closed {
+                    channel_id: channel.channel_id,
+                    address: channel.address,
+                    amount: U256::from(PRICE),
+                    nonce: U256::ZERO,
+                })
+                .await
+        );
+
+        let result = verify_and_update_channel(&state, sign(&signer, &next(&updated), b"")).await;
+        assert!(matches!(result, Err(AuthError::ChannelClosed)));
+    }
+
+    #[tokio::test]
+    async fn settles_channels_due_by_the_policy() {
+        let (state, backend, signer, channel) = setup();
+        let recipient = PrivateKeySigner::random();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+        verify_and_update_channel(&state, sign(&signer, &next(&updated), b""))
+            .await
+            .unwrap();
+
+        // The kept voucher pays out one price, not enough yet, and the channel expires in an hour
+        let settled = state
+            .settle_due_channels(
+                &recipient,
+                Some(U256::from(2 * PRICE)),
+                Some(Duration::from_secs(600)),
+            )
+            .await;
+        assert!(settled.is_empty());
+        assert!(backend.closes().is_empty());
+
+        // Closing to the hour
+        let settled = state
+            .settle_due_channels(&recipient, None, Some(Duration::from_secs(7200)))
+            .await;
+        assert_eq!(settled, vec![channel.channel_id]);
+        assert_eq!(backend.closes()[0].amount, U256::from(PRICE));
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Closed)
+        );
+
+        // Closed already
+        let settled = state
+            .settle_due_channels(&recipient, Some(U256::from(PRICE)), None)
+            .await;
+        assert!(settled.is_empty());
+    }
+
+    #[tokio::test]
+    async fn settlement_closes_channel() {
+        let (state, backend, signer, channel) = setup();
+        let recipient = PrivateKeySigner::random();
+
+        let request = sign(&signer, &channel, b"");
+        let signature = request.signature;
+        let updated = verify_and_update_channel(&state, request).await.unwrap();
+
+        // The voucher doesn't cover another body, the contract reverts
+        let result = state
+            .settle_channel(&recipient, &channel, &signature, Bytes::from_static(b"{}"))
+            .await;
+        assert!(matches!(result, Err(AuthError::ContractError(_))));
+        assert!(backend.closes().is_empty());
+
+        // A failed close leaves the channel usable
+        backend.fail_closes(true);
+        let result = state
+            .settle_channel(&recipient, &channel, &signature, Bytes::new())
+            .await;
+        assert!(matches!(result, Err(AuthError::ContractError(_))));
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Active)
+        );
+
+        backend.fail_closes(false);
+        state
+            .settle_channel(&recipient, &channel, &signature, Bytes::new())
+            .await
+            .unwrap();
+
+        assert_eq!(backend.closes().len(), 1);
+        assert_eq!(backend.closes()[0].sender, recipient.address());
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Closed)
+        );
+
+        let result = verify_and_update_channel(&state, sign(&signer, &next(&updated), b"")).await;
+        assert!(matches!(result, Err(AuthError::ChannelClosed)));
+    }
+
+    #[tokio::test]
+    async fn settles_what_was_served_after_a_top_up() {
+        let (state, backend, signer, channel) = setup();
+        let recipient = PrivateKeySigner::random();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+        let updated = verify_and_update_channel(&state, sign(&signer, &next(&updated), b""))
+            .await
+            .unwrap();
+        let before = state
+            .get_record(channel.channel_id)
+            .await
+            .unwrap()
+            .voucher
+            .unwrap();
+
+        // Topped up onchain, the sender's next voucher claims the new deposit
+        let mut info = backend.channel(channel.address).unwrap();
+        info.balance = U256::from(2 * DEPOSIT);
+        backend.set_channel(info);
+        let mut topped_up = next(&updated);
+        topped_up.balance += U256::from(DEPOSIT);
+        verify_and_update_channel(&state, sign(&signer, &topped_up, b""))
+            .await
+            .unwrap();
+
+        // The voucher from before the top-up would pay out the new deposit too
+        let result = state
+            .settle_channel(
+                &recipient,
+                &before.payment_channel,
+                &before.signature,
+                before.raw_body,
+            )
+            .await;
+        assert!(matches!(result, Err(AuthError::ClaimExceedsServed)));
+
+        let record = state.get_record(channel.channel_id).await.unwrap();
+        assert_eq!(record.served, U256::from(3 * PRICE));
+        let voucher = record.voucher.unwrap();
+        assert_eq!(voucher.payment_channel.nonce, topped_up.nonce);
+        state
+            .settle_channel(
+                &recipient,
+                &voucher.payment_channel,
+                &voucher.signature,
+                voucher.raw_body,
+            )
+            .await
+            .unwrap();
+
+        // What the sender signed for before the last request, none of the top-up
+        let closes = backend.closes();
+        assert_eq!(closes.len(), 1);
+        assert_eq!(closes[0].amount, U256::from(2 * PRICE));
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_unsettled_channels() {
+        let (state, _, signer, channel) = setup();
+        verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        // Long expired, or disputed, but what it was charged is still to be settled
+        for status in [ChannelStatus::Expired, ChannelStatus::Disputed] {
+            let adjustment = ChannelAdjustment {
+                expiration: Some(U256::from(1)),
+                status: Some(status),
+                ..Default::default()
+            };
+            state
+                .adjust_channel(channel.channel_id, &adjustment, "test")
+                .await
+                .unwrap();
+            assert_eq!(state.prune_channels(Duration::ZERO).await, 0);
+        }
+
+        // Nothing left to settle
+        let adjustment = ChannelAdjustment {
+            served: Some(U256::ZERO),
+            ..Default::default()
+        };
+        state
+            .adjust_channel(channel.channel_id, &adjustment, "test")
+            .await
+            .unwrap();
+        assert_eq!(state.prune_channels(Duration::ZERO).await, 1);
+        assert!(state.get_record(channel.channel_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn indexer_credits_deposits_once() {
+        let (state, backend, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        backend.emit(
+            channel.address,
+            &PaymentChannelContract::depositMade {
+                channel_id: channel.channel_id,
+                sender: channel.sender,
+                recipient: channel.recipient,
+                amount: U256::from(DEPOSIT),
+                newBalance: U256::from(2 * DEPOSIT),
+            },
+            10,
+        );
+        backend.set_block_number(20);
+
+        let mut config = IndexerConfig::new(Address::repeat_byte(9), channel.recipient, 0);
+        config.confirmations = 5;
+        let mut indexer = ChannelIndexer::new(state.clone(), config);
+
+        assert_eq!(indexer.poll().await.unwrap(), 1);
+        assert_eq!(indexer.checkpoint().unwrap().block_number, 15);
+
+        // The checkpoint block is replaced, the rescan sees the same deposit again
+        backend.reorg(15);
+        assert_eq!(indexer.poll().await.unwrap(), 0);
+
+        let channel = state.get_channel(channel.channel_id).await.unwrap();
+        assert_eq!(channel.balance, updated.balance + U256::from(DEPOSIT));
+
+        // The sender's next request already accounts for the deposit
+        let mut topped_up = next(&updated);
+        topped_up.balance = channel.balance;
+        assert!(
+            verify_and_update_channel(&state, sign(&signer, &topped_up, b""))
+                .await
+                .is_ok()
+        );
+    }
+
+    // `depositMade` carries the contract's new total, not an amount to add up
+    fn deposit(channel: &PaymentChannel, new_balance: u64) -> PaymentChannelContract::depositMade {
+        PaymentChannelContract::depositMade {
+            channel_id: channel.channel_id,
+            sender: channel.sender,
+            recipient: channel.recipient,
+            amount: U256::from(DEPOSIT),
+            newBalance: U256::from(new_balance),
+        }
+    }
+
+    fn closed(channel: &PaymentChannel) -> PaymentChannelContract::channelClosed {
+        PaymentChannelContract::channelClosed {
+            channel_id: channel.channel_id,
+            sender: channel.sender,
+            recipient: channel.recipient,
+            timestamp: U256::ZERO,
+            amount: U256::from(PRICE),
+            nonce: U256::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn indexer_applies_channel_events() {
+        let (state, backend, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        // Two deposits, the second one returned twice by the node
+        backend.emit(channel.address, &deposit(&channel, 2 * DEPOSIT), 10);
+        backend.emit(channel.address, &deposit(&channel, 3 * DEPOSIT), 11);
+        backend.emit(channel.address, &deposit(&channel, 3 * DEPOSIT), 11);
+        let expiration = channel.expiration + U256::from(7200);
+        backend.emit(
+            channel.address,
+            &PaymentChannelContract::expirationExtended {
+                channel_id: channel.channel_id,
+                sender: channel.sender,
+                recipient: channel.recipient,
+                expiration,
+            },
+            12,
+        );
+        backend.set_block_number(20);
+
+        let mut indexer = ChannelIndexer::new(
+            state.clone(),
+            IndexerConfig::new(Address::repeat_byte(9), channel.recipient, 0),
+        );
+        assert_eq!(indexer.poll().await.unwrap(), 3);
+
+        // Credited up to the new total, once
+        let record = state.get_record(channel.channel_id).await.unwrap();
+        assert_eq!(record.deposited, U256::from(3 * DEPOSIT));
+        assert_eq!(
+            record.channel.balance,
+            updated.balance + U256::from(2 * DEPOSIT)
+        );
+        assert_eq!(record.channel.expiration, expiration);
+
+        // Closed onchain, nothing is served against it anymore
+        backend.emit(channel.address, &closed(&channel), 21);
+        backend.set_block_number(30);
+        assert_eq!(indexer.poll().await.unwrap(), 1);
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Closed)
+        );
+        let mut topped_up = next(&updated);
+        topped_up.balance = record.channel.balance;
+        let result = verify_and_update_channel(&state, sign(&signer, &topped_up, b"")).await;
+        assert!(matches!(result, Err(AuthError::ChannelClosed)));
+    }
+
+    #[tokio::test]
+    async fn indexer_reverts_events_from_orphaned_blocks() {
+        let (state, backend, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        backend.emit(channel.address, &deposit(&channel, 2 * DEPOSIT), 12);
+        backend.emit(channel.address, &closed(&channel), 13);
+        backend.set_block_number(20);
+
+        let mut indexer = ChannelIndexer::new(
+            state.clone(),
+            IndexerConfig::new(Address::repeat_byte(9), channel.recipient, 0),
+        );
+        assert_eq!(indexer.poll().await.unwrap(), 2);
+        assert_eq!(indexer.checkpoint().unwrap().block_number, 15);
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Closed)
+        );
+
+        // Deeper than the confirmations, the new chain has the deposit later and no close
+        backend.reorg(12);
+        backend.emit(channel.address, &deposit(&channel, 2 * DEPOSIT), 14);
+        assert_eq!(indexer.poll().await.unwrap(), 1);
+
+        let record = state.get_record(channel.channel_id).await.unwrap();
+        assert_eq!(record.status, ChannelStatus::Active);
+        assert_eq!(record.deposited, U256::from(2 * DEPOSIT));
+        assert_eq!(
+            record.channel.balance,
+            updated.balance + U256::from(DEPOSIT)
+        );
+
+        // And without it either, the deposit is taken back
+        backend.reorg(14);
+        backend.set_block_number(25);
+        assert_eq!(indexer.poll().await.unwrap(), 0);
+
+        let record = state.get_record(channel.channel_id).await.unwrap();
+        assert_eq!(record.deposited, U256::from(DEPOSIT));
+        assert_eq!(record.channel.balance, updated.balance);
+        assert!(
+            verify_and_update_channel(&state, sign(&signer, &next(&updated), b""))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn reconciliation_quarantines_underfunded_channels() {
+        let (state, backend, signer, channel) = setup();
+
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        let report = reconcile_channels(&state, true).await;
+        assert_eq!(report.checked, 1);
+        assert!(report.is_clean());
+
+        // Partly withdrawn behind our back, below what was served
+        let mut info = backend.channel(channel.address).unwrap();
+        info.balance = U256::from(PRICE / 2);
+        backend.set_channel(info.clone());
+
+        let report = reconcile_channels(&state, true).await;
+        assert!(report.channels[0].quarantined);
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Disputed)
+        );
+
+        let result = verify_and_update_channel(&state, sign(&signer, &next(&updated), b"")).await;
+        assert!(matches!(result, Err(AuthError::ChannelUnavailable)));
+
+        // Then drained, the channel is closed for good
+        info.balance = U256::ZERO;
+        backend.set_channel(info);
+
+        let report = reconcile_channels(&state, true).await;
+        assert!(report.channels[0]
+            .discrepancies
+            .contains(&Discrepancy::ClosedOnchain));
+        assert_eq!(report.channels[0].status, ChannelStatus::Closed);
+        assert!(!report.channels[0].quarantined);
+        assert_eq!(
+            state.get_status(channel.channel_id).await,
+            Some(ChannelStatus::Closed)
+        );
+
+        let result = verify_and_update_channel(&state, sign(&signer, &next(&updated), b"")).await;
+        assert!(matches!(result, Err(AuthError::ChannelClosed)));
+
+        // Nothing open left to check
+        assert_eq!(reconcile_channels(&state, true).await.checked, 0);
+    }
+
+    #[test]
+    fn config_is_validated_and_overridden_by_env() {
+        let config = PipegateConfig::from_toml(include_str!("../pipegate.example.toml")).unwrap();
+
+        let pricing = config.pricing();
+        assert_eq!(pricing.price(&Method::GET, "/"), U256::from(1000));
+        assert_eq!(
+            pricing.price(&Method::POST, "/premium/report"),
+            U256::from(5000)
+        );
+        assert_eq!(
+            pricing.price(&Method::GET, "/premium/report"),
+            U256::from(1000)
+        );
+
+        let config = config
+            .with_vars(|name| (name == "PIPEGATE_PRICE").then(|| "2000".to_string()))
+            .unwrap();
+        assert_eq!(config.pricing.default, U256::from(2000));
+
+        let invalid = config
+            .clone()
+            .with_vars(|name| (name == "PIPEGATE_PRICE").then(|| "0".to_string()));
+        assert!(matches!(
+            invalid,
+            Err(ConfigError::Invalid { field, .. }) if field == "pricing.default"
+        ));
+
+        let mut invalid = config.clone();
+        invalid.pricing.routes[0].path = "premium".to_string();
+        assert!(matches!(
+            invalid.validate(),
+            Err(ConfigError::Invalid { field, .. }) if field == "pricing.routes[0].path"
+        ));
+
+        assert!(matches!(
+            PipegateConfig::from_toml("[network]\nrpc_url = \"http://localhost:8545\""),
+            Err(ConfigError::Parse(_))
+        ));
+
+        // Settling on its own needs something to settle on, and the key to settle with
+        let mut invalid = config.clone();
+        invalid.settlement.settle_interval = Some(60);
+        assert!(matches!(
+            invalid.validate(),
+            Err(ConfigError::Invalid { field, reason }) if field == "settlement.settle_interval" && reason.contains("settle_threshold")
+        ));
+        invalid.settlement.settle_threshold = Some(U256::from(PRICE));
+        assert!(matches!(
+            invalid.validate(),
+            Err(ConfigError::Invalid { field, reason }) if field == "settlement.settle_interval" && reason.contains("signer_key_env")
+        ));
+
+        // One indexer per recipient, following the factory
+        let mut indexed = config.clone();
+        indexed.indexer = Some(Default::default());
+        assert!(matches!(
+            indexed.validate(),
+            Err(ConfigError::Invalid { field, .. }) if field == "indexer"
+        ));
+        indexed.network.factory = Some(Address::repeat_byte(9));
+        indexed.network.recipients = vec![Address::repeat_byte(2), Address::repeat_byte(4)];
+        indexed.validate().unwrap();
+        let indexers = indexed.indexers();
+        assert_eq!(indexers.len(), 2);
+        assert_eq!(indexers[1].recipient, Address::repeat_byte(4));
+        assert_eq!(indexers[1].confirmations, 5);
+    }
+
+    #[tokio::test]
+    async fn tokens_and_proofs_follow_the_timestamp_window() {
+        let (state, _, signer, channel) = setup();
+        let state = state
+            .with_signer(PrivateKeySigner::random())
+            .with_access(AccessPolicy::new().with_allowed_sender(signer.address()))
+            .with_timestamp_window(Duration::from_secs(30))
+            .with_audit_log(AuditLog::new());
+        verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let token = |timestamp| sign_internal_token(&signer, &Method::GET, "/", timestamp).unwrap();
+        let proof = |timestamp| {
+            let message = create_resync_message(channel.channel_id, timestamp);
+            let signature = signer.sign_message_sync(&message).unwrap();
+            Signature::try_from(signature.as_bytes().as_slice()).unwrap()
+        };
+
+        // A minute old is past the window, even though under the 5 minutes default
+        let settings = state.settings();
+        let result = verify_internal_request(
+            &state,
+            &settings,
+            &Method::GET,
+            "/",
+            now - 60,
+            &token(now - 60),
+        )
+        .await;
+        assert!(matches!(result, Err(AuthError::Expired)));
+        let result = resync_channel(&state, channel.channel_id, now - 60, &proof(now - 60)).await;
+        assert!(matches!(result, Err(AuthError::Expired)));
+
+        // Nor stamped a minute ahead, it could be replayed until then
+        let result = verify_internal_request(
+            &state,
+            &settings,
+            &Method::GET,
+            "/",
+            now + 60,
+            &token(now + 60),
+        )
+        .await;
+        assert!(matches!(result, Err(AuthError::Expired)));
+
+        let result =
+            verify_internal_request(&state, &settings, &Method::GET, "/", now, &token(now)).await;
+        assert_eq!(result.unwrap(), signer.address());
+
+        // Logged along with the charge, for nothing
+        let entries = state.audit_log().unwrap().entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].sender, signer.address());
+        assert_eq!(entries[1].amount, U256::ZERO);
+        assert_eq!(entries[1].route, "GET /");
+        assert!(resync_channel(&state, channel.channel_id, now, &proof(now))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn channels_must_pay_accepted_recipients_and_tokens() {
+        let (state, _, signer, channel) = setup();
+
+        let strict = state.clone().with_recipients(vec![Address::repeat_byte(9)]);
+        let result = verify_and_update_channel(&strict, sign(&signer, &channel, b"")).await;
+        assert!(matches!(result, Err(AuthError::InvalidChannel)));
+
+        let strict = state.clone().with_tokens(vec![Address::repeat_byte(9)]);
+        let result = verify_and_update_channel(&strict, sign(&signer, &channel, b"")).await;
+        assert!(matches!(result, Err(AuthError::InvalidChannel)));
+
+        let accepted = state
+            .with_recipients(vec![channel.recipient])
+            .with_tokens(vec![Address::repeat_byte(3)]);
+        verify_and_update_channel(&accepted, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloaded_settings_are_audited_and_spare_inflight_requests() {
+        let audit_log = AuditLog::new();
+        let (state, _, signer, channel) = setup();
+        let state = state
+            .with_audit_log(audit_log.clone())
+            .with_rate_limit(1, Duration::from_secs(60));
+
+        // Taken by a request before the reload
+        let inflight = state.settings();
+
+        let settings = Settings {
+            rate_limit: 2,
+            ..(*state.settings()).clone()
+        };
+        let previous = state.reload_settings(settings, "test");
+        assert_eq!(previous.rate_limit, 1);
+        assert_eq!(inflight.rate_limit, 1);
+        assert_eq!(state.settings().rate_limit, 2);
+
+        // Two requests per window now
+        let updated = verify_and_update_channel(&state, sign(&signer, &channel, b""))
+            .await
+            .unwrap();
+        // While the one in flight is still held to a single one
+        let result =
+            verify_with_settings(&state, &inflight, sign(&signer, &next(&updated), b"")).await;
+        assert!(matches!(result, Err(AuthError::RateLimitExceeded)));
+        verify_and_update_channel(&state, sign(&signer, &next(&updated), b""))
+            .await
+            .unwrap();
+
+        let entries = audit_log.entries();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0]
+            .change
+            .as_ref()
+            .is_some_and(|change| change.contains("rate limit 2 per 60s")));
+        assert_eq!(entries[1].change, None);
+        assert_eq!(audit_log.verify(), Ok(()));
+
+        // Changes can't be edited either
+        let mut edited = entries.clone();
+        edited[0].change = Some("settings reloaded (test): nothing".to_string());
+        assert_eq!(
+            verify_entries(&edited),
+            Err(AuditViolation::Edited { index: 0 })
+        );
+    }
 }
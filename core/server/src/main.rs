@@ -2,44 +2,45 @@ use std::{env, str::FromStr};
 
 use alloy::{
     primitives::{Address, Bytes, U256},
-    signers::Signature,
+    signers::{local::PrivateKeySigner, Signature},
 };
 use axum::{routing::get, Router};
 use pipegate::{
     channel::{close_channel, ChannelState},
-    middleware::auth_middleware,
+    config::PipegateConfig,
     types::PaymentChannel,
 };
 
 #[tokio::main]
 pub async fn main() {
     // a mock server implementation using axum
-    // build our application with a route
+    // RPC URL, prices, limits... are read from `pipegate.toml`, see `pipegate.example.toml`
+    let config = PipegateConfig::load().expect("Invalid config");
 
-    // add middleware we created for protecting routes
-    // Create a new instance of our state
-    let rpc_url: alloy::transports::http::reqwest::Url =
-        "https://base-sepolia-rpc.publicnode.com".parse().unwrap();
-
-    // Amount is not supposed to be in the decimal format, so parsed with the decimals of that token
+    // Amounts are not supposed to be in the decimal format, so parsed with the decimals of that token
     // E.g. if USDC is being used 1USDC = 1000000 after 6 decimal places in case of the USDC token
-    let payment_amount = U256::from(1000); // 0.001 USDC in this case
+    let pipegate = config.build().expect("Invalid config");
+    pipegate.spawn_tasks();
+    // Prices, allowlists and rate limits are reloaded from the file on SIGHUP
+    #[cfg(unix)]
+    pipegate.spawn_reload_on_sighup(PipegateConfig::path());
 
-    let state = ChannelState::new(rpc_url.clone());
-
-    let app = Router::new()
+    let mut app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
-        .layer(axum::middleware::from_fn(move |req, next| {
-            let state = state.clone();
-            auth_middleware(state, payment_amount, req, next)
-        }));
-
-    // run our server on localhost:3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+        // add middleware we created for protecting routes
+        .layer(pipegate.layer());
+    // the admin API, if configured, is added after the middleware so it isn't charged
+    if let Some(admin) = pipegate.admin_router(PipegateConfig::path()) {
+        app = app.nest("/admin", admin);
+    }
+
+    // run our server on the configured address
+    let listener = tokio::net::TcpListener::bind(config.server.listen)
+        .await
+        .unwrap();
+    println!("Listening on: http://{}", config.server.listen);
     axum::serve(listener, app).await.unwrap();
-
-    println!("Listening on: http://localhost:3000");
 }
 
 pub async fn close_and_withdraw(_state: &ChannelState) {
@@ -60,17 +61,14 @@ pub async fn close_and_withdraw(_state: &ChannelState) {
     let rpc_url: alloy::transports::http::reqwest::Url =
         "https://base-sepolia-rpc.publicnode.com".parse().unwrap();
 
-    let private_key = env::var("PRIVATE_KEY").expect("PRIVATE_KEY must be set");
+    let signer: PrivateKeySigner = env::var("PRIVATE_KEY")
+        .expect("PRIVATE_KEY must be set")
+        .parse()
+        .expect("Invalid private key");
 
     let raw_body = Bytes::from("0x");
 
-    let tx_hash = close_channel(
-        rpc_url,
-        private_key.as_str(),
-        &payment_channel,
-        &signature,
-        raw_body,
-    );
+    let tx_hash = close_channel(rpc_url, &signer, &payment_channel, &signature, raw_body);
 
     println!("Transaction Hash: {:?}", tx_hash.await);
 }
@@ -1,17 +1,33 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use alloy::{hex, primitives::U256, signers::Signature};
+use alloy::{
+    hex,
+    primitives::{Address, U256},
+    signers::Signature,
+};
 use axum::{
-    body::Body,
-    http::{Request, StatusCode},
+    body::{Body, HttpBody},
+    http::{request::Parts, HeaderMap, Method, Request, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+    Json,
 };
 
 use crate::{
+    access::{verify_internal_request, INTERNAL_HEADER},
     channel::ChannelState,
-    types::{PaymentChannel, SignedRequest},
-    verify::verify_and_update_channel,
+    config::Settings,
+    cors::{insert_cors_headers, is_preflight, preflight_response},
+    error::AuthError,
+    extract::PaymentContext,
+    idempotency::{CachedResponse, Outcome, IDEMPOTENCY_HEADER},
+    receipt::{sign_receipt, RECEIPT_HEADER},
+    resync::{resync_channel, RESYNC_PATH},
+    types::{CanonicalRequest, MessageScheme, PaymentChannel, SignedRequest},
+    verify::verify_with_settings,
 };
 
 pub async fn auth_middleware(
@@ -19,14 +35,255 @@ pub async fn auth_middleware(
     payment_amount: U256, // defined by the developer creating the API, and should match with what user agreed with in the signed request
     request: Request<Body>,
     next: Next,
+) -> Result<Response, StatusCode> {
+    let settings = state.settings();
+    middleware_with_settings(state, settings, payment_amount, request, next).await
+}
+
+// The whole request goes through with `settings`, the ones it was priced with, even if they're reloaded meanwhile
+pub(crate) async fn middleware_with_settings(
+    state: ChannelState,
+    settings: Arc<Settings>,
+    payment_amount: U256,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if !state.browser_mode() {
+        return charge_request(state, &settings, payment_amount, request, next).await;
+    }
+
+    // Preflights carry no payment headers, and errors must be readable by the browser too
+    if is_preflight(&request) {
+        return Ok(preflight_response(request.headers()));
+    }
+    let mut response = match charge_request(state, &settings, payment_amount, request, next).await {
+        Ok(response) => response,
+        Err(status) => status.into_response(),
+    };
+    insert_cors_headers(&mut response);
+
+    Ok(response)
+}
+
+async fn charge_request(
+    state: ChannelState,
+    settings: &Settings,
+    payment_amount: U256,
+    request: Request<Body>,
+    next: Next,
 ) -> Result<Response, StatusCode> {
     println!("\n=== auth_middleware ===");
     println!(" === new request ===");
 
+    // The resync endpoint is answered here, without payment
+    if request.method() == Method::GET
+        && request.uri().path() == RESYNC_PATH
+        && state.signer().is_some()
+    {
+        return resync(&state, request.headers()).await;
+    }
+
+    // Free routes skip the payment
+    if settings
+        .access
+        .is_free(request.method(), request.uri().path())
+    {
+        println!("Free route: {} {}", request.method(), request.uri().path());
+        return Ok(next.run(request).await);
+    }
+
+    // Internal services sign a token instead of paying
+    if request.headers().contains_key(INTERNAL_HEADER) {
+        let sender = internal(
+            &state,
+            settings,
+            request.method(),
+            request.uri().path(),
+            request.headers(),
+        )
+        .await?;
+        println!(
+            "Internal request from {}: {} {}",
+            sender,
+            request.method(),
+            request.uri().path()
+        );
+        return Ok(next.run(request).await);
+    }
+
+    // Get request body
+    let (mut parts, body) = request.into_parts();
+    let body_bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            println!("Failed: Body decode");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    println!("Body: {}", String::from_utf8_lossy(&body_bytes));
+
+    let (signed_request, now) = parse_signed_request(
+        &parts,
+        &body_bytes,
+        payment_amount,
+        state.timestamp_window(),
+    )?;
+    let payment_nonce = signed_request.payment_channel.nonce;
+
+    // What the receipt commits to
+    let signed_payload = state.signer().map(|_| signed_request.payload());
+
+    // Retries carrying the same idempotency key get the outcome of the first request, for that the key has to be signed
+    let idempotency = match (parts.headers.get(IDEMPOTENCY_HEADER), state.idempotency()) {
+        (Some(key), Some(cache)) => {
+            let key = key
+                .to_str()
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+                .to_string();
+            let signed = signed_request.request.as_ref().is_some_and(|request| {
+                request
+                    .headers
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case(IDEMPOTENCY_HEADER))
+            });
+            if !signed {
+                println!("Failed: Idempotency key not signed");
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Some((cache.clone(), key))
+        }
+        _ => None,
+    };
+
+    // Released if the request fails before the charge, kept if it's dropped after
+    let mut reservation = None;
+    if let Some((cache, key)) = &idempotency {
+        match cache.begin(key, &signed_request) {
+            Ok(Outcome::Fresh(fresh)) => reservation = Some(fresh),
+            Ok(Outcome::Replay {
+                payment_channel,
+                response,
+            }) => {
+                println!("Replaying request with idempotency key {}", key);
+                let response = match response {
+                    Some(cached) => {
+                        let mut response = Response::new(Body::from(cached.body));
+                        *response.status_mut() = cached.status;
+                        *response.headers_mut() = cached.headers;
+                        response
+                    }
+                    // The response wasn't kept, running the handler again would serve it for free
+                    None => {
+                        println!("Failed: Response for idempotency key {} not kept", key);
+                        let mut response =
+                            StatusCode::from(AuthError::AlreadyServed).into_response();
+                        insert_payment_headers(&mut response, &payment_channel, now);
+                        response
+                    }
+                };
+                return Ok(response);
+            }
+            Err(e) => return Err(StatusCode::from(e)),
+        }
+    }
+
+    // Validate the headers against the payment channel state and return the response
+    match verify_with_settings(&state, settings, signed_request).await {
+        Ok(payment_channel) => {
+            if let Some(reservation) = reservation.as_mut() {
+                reservation.charged(payment_channel.clone());
+            }
+
+            // Let the handlers know who paid
+            parts.extensions.insert(PaymentContext {
+                sender: payment_channel.sender,
+                channel_id: payment_channel.channel_id,
+                nonce: payment_nonce,
+                amount: payment_amount,
+                balance: payment_channel.balance,
+            });
+            let request = Request::from_parts(parts, Body::from(body_bytes));
+
+            // Modify the response headers to include the payment channel data
+            let mut response = next.run(request).await;
+            insert_payment_headers(&mut response, &payment_channel, now);
+
+            // Streamed responses ( the gateway's ) have no known length, they are passed on as they come rather than buffered
+            let streamed = response.body().size_hint().exact().is_none();
+
+            // Sign a receipt for the request and the response it got, the response body is left out when streamed
+            if let (Some(signer), Some(signed_payload)) = (state.signer(), signed_payload) {
+                let (mut response_parts, body) = response.into_parts();
+                let (hashed, body) = if streamed {
+                    (None, body)
+                } else {
+                    let bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|_| {
+                        println!("Failed: Response body read");
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?;
+                    (Some(bytes.clone()), Body::from(bytes))
+                };
+
+                let receipt = sign_receipt(
+                    signer,
+                    payment_channel.channel_id,
+                    payment_nonce,
+                    payment_amount,
+                    payment_channel.balance,
+                    &signed_payload,
+                    hashed.as_deref(),
+                    now,
+                )
+                .map_err(StatusCode::from)?;
+                response_parts.headers.insert(
+                    RECEIPT_HEADER,
+                    serde_json::to_string(&receipt).unwrap().parse().unwrap(),
+                );
+
+                response = Response::from_parts(response_parts, body);
+            }
+
+            if let (Some((cache, _)), Some(reservation)) = (idempotency, reservation) {
+                let cached = if cache.caches_responses() && !streamed {
+                    let (response_parts, body) = response.into_parts();
+                    let body = axum::body::to_bytes(body, usize::MAX).await.map_err(|_| {
+                        println!("Failed: Response body read");
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?;
+                    let cached = CachedResponse {
+                        status: response_parts.status,
+                        headers: response_parts.headers.clone(),
+                        body: body.clone(),
+                    };
+                    response = Response::from_parts(response_parts, Body::from(body));
+                    Some(cached)
+                } else {
+                    None
+                };
+                reservation.complete(cached);
+            }
+
+            println!(" === end request ===\n");
+
+            Ok(response)
+        }
+        // Dropping the reservation releases the key
+        Err(e) => Err(StatusCode::from(e)),
+    }
+}
+
+// Parse the payment headers of a request into the signed request to verify, along with the current time
+// Shared by the middleware and the `Paid` extractor
+pub(crate) fn parse_signed_request(
+    parts: &Parts,
+    body_bytes: &[u8],
+    payment_amount: U256,
+    timestamp_window: u64,
+) -> Result<(SignedRequest, u64), StatusCode> {
     // parse the request to retrieve the required headers
     // Check timestamp first
-    let timestamp = request
-        .headers()
+    let timestamp = parts
+        .headers
         .get("X-Timestamp")
         .and_then(|t| t.to_str().ok())
         .and_then(|t| t.parse::<u64>().ok())
@@ -39,32 +296,43 @@ pub async fn auth_middleware(
         .unwrap()
         .as_secs();
 
-    if now - timestamp > 300 {
+    // Either way, the client's clock can be ahead of ours
+    if timestamp.abs_diff(now) > timestamp_window {
         return Err(StatusCode::REQUEST_TIMEOUT);
     }
 
     // Get and validate all required headers
-    let signature = request
-        .headers()
+    let signature = parts
+        .headers
         .get("X-Signature")
         .ok_or(StatusCode::UNAUTHORIZED)?
         .to_str()
         .map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    let message = request
-        .headers()
+    let message = parts
+        .headers
         .get("X-Message")
         .ok_or(StatusCode::UNAUTHORIZED)?
         .to_str()
         .map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    let payment_data = request
-        .headers()
+    let payment_data = parts
+        .headers
         .get("X-Payment")
         .ok_or(StatusCode::UNAUTHORIZED)?
         .to_str()
         .map_err(|_| StatusCode::BAD_REQUEST)?;
 
+    // Message format, legacy unless asked otherwise
+    let scheme = match parts.headers.get("X-Message-Version") {
+        Some(version) => version
+            .to_str()
+            .ok()
+            .and_then(|version| version.parse::<MessageScheme>().ok())
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        None => MessageScheme::Legacy,
+    };
+
     // Print all the headers
     println!("Signature: {}", signature);
     println!("Message: {}", message);
@@ -95,48 +363,121 @@ pub async fn auth_middleware(
         StatusCode::BAD_REQUEST
     })?;
 
-    // Get request body
-    let (parts, body) = request.into_parts();
-    let body_bytes = match axum::body::to_bytes(body, usize::MAX).await {
-        Ok(bytes) => bytes,
-        Err(_) => {
-            println!("Failed: Body decode");
-            return Err(StatusCode::BAD_REQUEST);
+    // The client signed the canonical request rather than the bare body
+    let canonical_request = match parts.headers.get("X-Signed-Headers") {
+        Some(signed_headers) => {
+            let signed_headers = signed_headers
+                .to_str()
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            Some(
+                CanonicalRequest::from_parts(
+                    &parts.method,
+                    &parts.uri,
+                    &parts.headers,
+                    signed_headers,
+                    timestamp,
+                )
+                .map_err(|e| {
+                    println!("Failed: Canonical request - Error {}", e);
+                    StatusCode::BAD_REQUEST
+                })?,
+            )
         }
+        None => None,
     };
-    println!("Body: {}", String::from_utf8_lossy(&body_bytes));
 
     let signed_request = SignedRequest {
+        scheme,
         message,
         signature,
         payment_channel,
         payment_amount,
         body_bytes: body_bytes.to_vec(),
+        request: canonical_request,
+        route: Some(format!("{} {}", parts.method, parts.uri.path())),
     };
 
-    // Validate the headers against the payment channel state and return the response
-    match verify_and_update_channel(&state, signed_request).await {
-        Ok(payment_channel) => {
-            let request = Request::from_parts(parts, Body::from(body_bytes));
+    Ok((signed_request, now))
+}
 
-            // Modify the response headers to include the payment channel data
-            let mut response = next.run(request).await;
-            let headers_mut = response.headers_mut();
-
-            // convert the payment channel json into string and then return that in the header
-            headers_mut.insert(
-                "X-Payment",
-                serde_json::to_string(&payment_channel)
-                    .unwrap()
-                    .parse()
-                    .unwrap(),
-            );
-            headers_mut.insert("X-Timestamp", now.to_string().parse().unwrap());
+// Check the internal token of the request, see `access::verify_internal_request`
+async fn internal(
+    state: &ChannelState,
+    settings: &Settings,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+) -> Result<Address, StatusCode> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(StatusCode::BAD_REQUEST)
+    };
 
-            println!(" === end request ===\n");
+    let timestamp = header("X-Timestamp")?
+        .parse::<u64>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let signature = hex::decode(header(INTERNAL_HEADER)?.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
 
-            Ok(response)
+    verify_internal_request(state, settings, method, path, timestamp, &signature)
+        .await
+        .map_err(|e| {
+            println!("Failed: Internal request - Error {}", e);
+            StatusCode::from(e)
+        })
+}
+
+// Send the client the latest state of its channel, see `resync::resync_channel`
+async fn resync(state: &ChannelState, headers: &HeaderMap) -> Result<Response, StatusCode> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(StatusCode::BAD_REQUEST)
+    };
+
+    let channel_id = header("X-Channel-Id")?
+        .parse::<U256>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let timestamp = header("X-Timestamp")?
+        .parse::<u64>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let signature = hex::decode(header("X-Signature")?.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    match resync_channel(state, channel_id, timestamp, &signature).await {
+        Ok(snapshot) => Ok(Json(snapshot).into_response()),
+        Err(e) => {
+            println!("Failed: Resync - Error {}", e);
+            Err(StatusCode::from(e))
         }
-        Err(e) => Err(StatusCode::from(e)),
     }
 }
+
+fn insert_payment_headers(response: &mut Response, payment_channel: &PaymentChannel, now: u64) {
+    response
+        .headers_mut()
+        .extend(payment_headers(payment_channel, now));
+}
+
+// Headers handing the client the updated state of its channel
+pub(crate) fn payment_headers(payment_channel: &PaymentChannel, now: u64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    // convert the payment channel json into string and then return that in the header
+    headers.insert(
+        "X-Payment",
+        serde_json::to_string(payment_channel)
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    headers.insert("X-Timestamp", now.to_string().parse().unwrap());
+    headers
+}
@@ -0,0 +1,136 @@
+// Per-request receipts signed with the server key
+// Sent back in the `X-Receipt` header, they commit to what was charged for which request and which response,
+// so that the client, or an auditor, can prove what was paid for what was delivered
+
+use alloy::{
+    dyn_abi::DynSolValue,
+    primitives::{keccak256, Address, FixedBytes, U256},
+    signers::{local::PrivateKeySigner, Signature, SignerSync},
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::error::AuthError;
+
+pub const RECEIPT_HEADER: &str = "X-Receipt";
+
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub nonce: U256, // Nonce of the voucher paying for the request
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: U256, // Charged for the request
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub balance: U256, // Left in the channel afterwards
+
+    pub request_hash: FixedBytes<32>, // keccak256 of the signed payload, the body or the canonical request
+    pub response_hash: FixedBytes<32>, // keccak256 of the response body, zero for streamed responses
+    pub timestamp: u64,
+    pub server: Address,
+    pub signature: Signature, // Over `create_receipt_message`
+}
+
+// What the server signs for the receipt
+#[allow(clippy::too_many_arguments)]
+pub fn create_receipt_message(
+    channel_id: U256,
+    nonce: U256,
+    amount: U256,
+    balance: U256,
+    request_hash: FixedBytes<32>,
+    response_hash: FixedBytes<32>,
+    timestamp: u64,
+) -> Vec<u8> {
+    let message = DynSolValue::Tuple(vec![
+        DynSolValue::String("PipeGate receipt".to_string()),
+        DynSolValue::Uint(channel_id, 256),
+        DynSolValue::Uint(nonce, 256),
+        DynSolValue::Uint(amount, 256),
+        DynSolValue::Uint(balance, 256),
+        DynSolValue::FixedBytes(request_hash, 32),
+        DynSolValue::FixedBytes(response_hash, 32),
+        DynSolValue::Uint(U256::from(timestamp), 256),
+    ]);
+
+    keccak256(message.abi_encode_packed()).to_vec()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn sign_receipt(
+    signer: &PrivateKeySigner,
+    channel_id: U256,
+    nonce: U256,
+    amount: U256,
+    balance: U256,
+    request: &[u8],
+    response: Option<&[u8]>, // None for a streamed response, not buffered to be hashed
+    timestamp: u64,
+) -> Result<Receipt, AuthError> {
+    let request_hash = keccak256(request);
+    let response_hash = response.map(keccak256).unwrap_or_default();
+
+    let message = create_receipt_message(
+        channel_id,
+        nonce,
+        amount,
+        balance,
+        request_hash,
+        response_hash,
+        timestamp,
+    );
+    let signature = signer
+        .sign_message_sync(&message)
+        .map_err(|_| AuthError::InvalidConfig)?;
+
+    Ok(Receipt {
+        channel_id,
+        nonce,
+        amount,
+        balance,
+        request_hash,
+        response_hash,
+        timestamp,
+        server: signer.address(),
+        signature: Signature::try_from(signature.as_bytes().as_slice())
+            .map_err(|_| AuthError::InvalidConfig)?,
+    })
+}
+
+impl Receipt {
+    // The receipt was signed by `server`
+    pub fn verify(&self, server: Address) -> bool {
+        let message = create_receipt_message(
+            self.channel_id,
+            self.nonce,
+            self.amount,
+            self.balance,
+            self.request_hash,
+            self.response_hash,
+            self.timestamp,
+        );
+
+        self.server == server
+            && self
+                .signature
+                .recover_address_from_msg(message)
+                .is_ok_and(|address| address == server)
+    }
+
+    // The response body was hashed, it wasn't streamed
+    pub fn covers_response(&self) -> bool {
+        !self.response_hash.is_zero()
+    }
+
+    // The receipt was signed by `server`, for this request and this response
+    pub fn verify_exchange(&self, server: Address, request: &[u8], response: &[u8]) -> bool {
+        self.request_hash == keccak256(request)
+            && self.response_hash == keccak256(response)
+            && self.verify(server)
+    }
+}
@@ -0,0 +1,204 @@
+// Periodic reconciliation of the local channel state with the channel contracts
+// Local balances are only ever updated in memory, this checks them against what the contracts actually hold
+
+use std::time::Duration;
+
+use alloy::primitives::U256;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::task::JoinHandle;
+
+use crate::{
+    channel::ChannelState,
+    types::{ChannelInfo, ChannelRecord, ChannelStatus},
+};
+
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    // The contract holds a different amount than what we last observed
+    Balance {
+        #[serde_as(as = "DisplayFromStr")]
+        local: U256,
+        #[serde_as(as = "DisplayFromStr")]
+        onchain: U256,
+    },
+    // The contract holds less than what was already served against the channel
+    Underfunded {
+        #[serde_as(as = "DisplayFromStr")]
+        served: U256,
+        #[serde_as(as = "DisplayFromStr")]
+        onchain: U256,
+    },
+    Expiration {
+        #[serde_as(as = "DisplayFromStr")]
+        local: U256,
+        #[serde_as(as = "DisplayFromStr")]
+        onchain: U256,
+    },
+    // The contract was drained while the channel is still open locally
+    ClosedOnchain,
+    // The contract couldn't be read
+    Unreachable {
+        error: String,
+    },
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelReport {
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: U256,
+    pub status: ChannelStatus,
+    pub discrepancies: Vec<Discrepancy>,
+    pub quarantined: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReconciliationReport {
+    pub checked: usize,
+    pub channels: Vec<ChannelReport>, // Only the channels with discrepancies
+}
+
+impl ReconciliationReport {
+    pub fn is_clean(&self) -> bool {
+        self.channels.is_empty()
+    }
+}
+
+// Compare the local state of the channel with the contract
+pub fn compare(record: &ChannelRecord, info: &ChannelInfo) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+
+    // What was charged against the channel, the vouchers let us claim it from the contract
+    let served = record.served;
+
+    if info.balance.is_zero() && !record.deposited.is_zero() {
+        discrepancies.push(Discrepancy::ClosedOnchain);
+    } else if info.balance != record.deposited {
+        discrepancies.push(Discrepancy::Balance {
+            local: record.deposited,
+            onchain: info.balance,
+        });
+    }
+
+    if info.balance < served {
+        discrepancies.push(Discrepancy::Underfunded {
+            served,
+            onchain: info.balance,
+        });
+    }
+
+    if info.expiration != record.channel.expiration {
+        discrepancies.push(Discrepancy::Expiration {
+            local: record.channel.expiration,
+            onchain: info.expiration,
+        });
+    }
+
+    discrepancies
+}
+
+// Check every open channel against its contract
+// The channels drained onchain are moved to `Closed`, there is nothing left to pay with
+// With `quarantine`, the channels that can't cover what was already served are moved to `Disputed` and stop serving requests
+pub async fn reconcile_channels(state: &ChannelState, quarantine: bool) -> ReconciliationReport {
+    // Work on a snapshot, so that requests aren't blocked by the onchain calls
+    let records: Vec<ChannelRecord> = state
+        .channels
+        .read()
+        .await
+        .values()
+        .filter(|record| {
+            matches!(
+                record.status,
+                ChannelStatus::Active | ChannelStatus::Expired | ChannelStatus::Disputed
+            )
+        })
+        .cloned()
+        .collect();
+
+    let mut report = ReconciliationReport {
+        checked: records.len(),
+        channels: Vec::new(),
+    };
+
+    for record in records {
+        let channel_id = record.channel.channel_id;
+
+        let discrepancies = match state.read_channel(record.channel.address).await {
+            Ok(info) => compare(&record, &info),
+            Err(e) => vec![Discrepancy::Unreachable {
+                error: e.to_string(),
+            }],
+        };
+
+        if discrepancies.is_empty() {
+            continue;
+        }
+
+        let closed = discrepancies
+            .iter()
+            .any(|d| matches!(d, Discrepancy::ClosedOnchain))
+            && state.mark_closed(channel_id).await.is_ok();
+        let underfunded = discrepancies
+            .iter()
+            .any(|d| matches!(d, Discrepancy::Underfunded { .. }));
+
+        let quarantined = !closed
+            && quarantine
+            && underfunded
+            && state
+                .transition(channel_id, ChannelStatus::Disputed)
+                .await
+                .is_ok();
+
+        println!(
+            "Channel {} out of sync: {:?}{}",
+            channel_id,
+            discrepancies,
+            if closed {
+                ", closed"
+            } else if quarantined {
+                ", quarantined"
+            } else {
+                ""
+            }
+        );
+
+        report.channels.push(ChannelReport {
+            channel_id,
+            status: if closed {
+                ChannelStatus::Closed
+            } else if quarantined {
+                ChannelStatus::Disputed
+            } else {
+                record.status
+            },
+            discrepancies,
+            quarantined,
+        });
+    }
+
+    report
+}
+
+// Reconcile the channels in the background, every `interval`
+pub fn spawn_reconciler(
+    state: ChannelState,
+    interval: Duration,
+    quarantine: bool,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let report = reconcile_channels(&state, quarantine).await;
+            println!(
+                "Reconciled {} channels, {} out of sync",
+                report.checked,
+                report.channels.len()
+            );
+        }
+    })
+}
@@ -0,0 +1,146 @@
+// Channel state resync, for the clients that lost their local state ( latest nonce and balance )
+// The sender proves it owns the channel by signing the channel id with a fresh timestamp,
+// the server answers with its latest state of the channel, signed with the server key, along with the last voucher it holds
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use alloy::{
+    dyn_abi::DynSolValue,
+    primitives::{keccak256, Address, U256},
+    signers::{local::PrivateKeySigner, Signature, SignerSync},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    channel::ChannelState,
+    error::AuthError,
+    types::{ChannelStatus, ChannelVoucher, PaymentChannel},
+};
+
+// Mounted by the middleware, `GET` with the `X-Channel-Id`, `X-Timestamp` and `X-Signature` headers
+pub const RESYNC_PATH: &str = "/pipegate/channel";
+
+// What the sender signs to prove it owns the channel
+pub fn create_resync_message(channel_id: U256, timestamp: u64) -> Vec<u8> {
+    let message = DynSolValue::Tuple(vec![
+        DynSolValue::String("PipeGate resync".to_string()),
+        DynSolValue::Uint(channel_id, 256),
+        DynSolValue::Uint(U256::from(timestamp), 256),
+    ]);
+
+    keccak256(message.abi_encode_packed()).to_vec()
+}
+
+// What the server signs over the channel state it returns
+pub fn create_state_message(
+    channel_id: U256,
+    balance: U256,
+    nonce: U256,
+    timestamp: u64,
+) -> Vec<u8> {
+    let message = DynSolValue::Tuple(vec![
+        DynSolValue::String("PipeGate state".to_string()),
+        DynSolValue::Uint(channel_id, 256),
+        DynSolValue::Uint(balance, 256),
+        DynSolValue::Uint(nonce, 256),
+        DynSolValue::Uint(U256::from(timestamp), 256),
+    ]);
+
+    keccak256(message.abi_encode_packed()).to_vec()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelSnapshot {
+    pub channel: PaymentChannel, // Balance left and highest nonce used, continue from there
+    pub status: ChannelStatus,
+    pub voucher: Option<ChannelVoucher>, // Last voucher held by the server, signed by the sender
+    pub timestamp: u64,
+    pub server: Address,      // Address the snapshot is signed with
+    pub signature: Signature, // Over `create_state_message`
+}
+
+impl ChannelSnapshot {
+    // Client side check that the snapshot comes from the expected server
+    pub fn verify(&self, server: Address) -> bool {
+        let message = create_state_message(
+            self.channel.channel_id,
+            self.channel.balance,
+            self.channel.nonce,
+            self.timestamp,
+        );
+
+        self.server == server
+            && self
+                .signature
+                .recover_address_from_msg(message)
+                .is_ok_and(|address| address == server)
+    }
+}
+
+// Check the sender's proof and return the signed state of the channel
+pub async fn resync_channel(
+    state: &ChannelState,
+    channel_id: U256,
+    timestamp: u64,
+    signature: &Signature,
+) -> Result<ChannelSnapshot, AuthError> {
+    let signer = state.signer().ok_or(AuthError::InvalidConfig)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    // Fresh within the same window as the payments
+    if timestamp.abs_diff(now) > state.timestamp_window() {
+        return Err(AuthError::Expired);
+    }
+
+    let record = state
+        .channels
+        .read()
+        .await
+        .get(&channel_id)
+        .cloned()
+        .ok_or(AuthError::ChannelNotFound)?;
+
+    let sender = signature
+        .recover_address_from_msg(create_resync_message(channel_id, timestamp))
+        .map_err(|_| AuthError::InvalidSignature)?;
+    if sender != record.channel.sender {
+        println!(
+            "Failed: Resync requested by {} for channel {}",
+            sender, channel_id
+        );
+        return Err(AuthError::InvalidSignature);
+    }
+
+    sign_snapshot(signer, &record.channel, record.status, record.voucher, now)
+}
+
+fn sign_snapshot(
+    signer: &PrivateKeySigner,
+    channel: &PaymentChannel,
+    status: ChannelStatus,
+    voucher: Option<ChannelVoucher>,
+    timestamp: u64,
+) -> Result<ChannelSnapshot, AuthError> {
+    let message = create_state_message(
+        channel.channel_id,
+        channel.balance,
+        channel.nonce,
+        timestamp,
+    );
+    let signature = signer
+        .sign_message_sync(&message)
+        .map_err(|_| AuthError::InvalidConfig)?;
+
+    Ok(ChannelSnapshot {
+        channel: channel.clone(),
+        status,
+        voucher,
+        timestamp,
+        server: signer.address(),
+        signature: Signature::try_from(signature.as_bytes().as_slice())
+            .map_err(|_| AuthError::InvalidConfig)?,
+    })
+}
@@ -0,0 +1,332 @@
+// Test support, a local stand-in for the JSON-RPC node
+// Answers the PaymentChannel and ChannelFactory reads over `eth_call`, and accepts the close and register transactions,
+// so that the real alloy code paths can be exercised offline. Enabled with the `test-utils` feature
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use alloy::{
+    consensus::{Transaction, TxEnvelope},
+    eips::eip2718::Decodable2718,
+    hex,
+    primitives::{keccak256, Address, Bytes, FixedBytes, U256},
+    rpc::types::{Block, BlockTransactions},
+    signers::Signature,
+    sol_types::{SolCall, SolValue},
+    transports::http::reqwest::Url,
+};
+use axum::{extract::State, routing::post, Json, Router};
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{
+    channel::{ChannelFactoryContract, PaymentChannelContract},
+    types::ChannelInfo,
+    utils::create_message,
+};
+
+// A close transaction received by the fake node
+#[derive(Clone, Debug)]
+pub struct FakeClose {
+    pub from: Address, // Recovered signer of the transaction
+    pub channel: Address,
+    pub balance: U256,
+    pub nonce: U256,
+    pub raw_body: Bytes,
+    pub signature: Bytes,
+}
+
+#[derive(Debug, Default)]
+struct FakeChain {
+    chain_id: u64,
+    block_number: u64,
+    blocks: HashMap<u64, Vec<FixedBytes<32>>>, // Transactions included in each block
+    channels: HashMap<Address, ChannelInfo>,
+    factory: HashMap<(Address, U256), Address>,
+    pricing: HashMap<(Address, Address), U256>, // Price registered by each recipient in each factory
+    balances: HashMap<Address, U256>,
+    nonces: HashMap<Address, u64>,
+    closes: Vec<FakeClose>,
+    methods: Vec<String>, // Every method called, in order
+}
+
+pub struct FakeRpc {
+    chain: Arc<Mutex<FakeChain>>,
+    url: Url,
+    server: JoinHandle<()>,
+}
+
+impl FakeRpc {
+    // Start the fake node on a random local port
+    pub async fn spawn(chain_id: u64) -> Self {
+        let chain = Arc::new(Mutex::new(FakeChain {
+            chain_id,
+            block_number: 1,
+            ..Default::default()
+        }));
+
+        let app = Router::new()
+            .route("/", post(handle))
+            .with_state(chain.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        Self { chain, url, server }
+    }
+
+    pub fn url(&self) -> Url {
+        self.url.clone()
+    }
+
+    // Add or replace a channel contract
+    pub fn set_channel(&self, info: ChannelInfo) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.channels.insert(info.address, info);
+    }
+
+    pub fn channel(&self, address: Address) -> Option<ChannelInfo> {
+        self.chain.lock().unwrap().channels.get(&address).cloned()
+    }
+
+    // Register the channel contract in the factory mapping
+    pub fn register_channel(&self, factory: Address, channel_id: U256, address: Address) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.factory.insert((factory, channel_id), address);
+    }
+
+    // Price registered by the recipient in the factory, zero if none
+    pub fn pricing(&self, factory: Address, recipient: Address) -> U256 {
+        let chain = self.chain.lock().unwrap();
+        chain
+            .pricing
+            .get(&(factory, recipient))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Native balance of an account, for the gas
+    pub fn set_balance(&self, address: Address, balance: U256) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.balances.insert(address, balance);
+    }
+
+    // Close transactions received so far
+    pub fn closes(&self) -> Vec<FakeClose> {
+        self.chain.lock().unwrap().closes.clone()
+    }
+
+    // JSON-RPC methods called so far, in order
+    pub fn methods(&self) -> Vec<String> {
+        self.chain.lock().unwrap().methods.clone()
+    }
+}
+
+impl Drop for FakeRpc {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn handle(
+    State(chain): State<Arc<Mutex<FakeChain>>>,
+    Json(body): Json<Value>,
+) -> Json<Value> {
+    let mut chain = chain.lock().unwrap();
+
+    // Batches are answered request by request
+    match body {
+        Value::Array(requests) => Json(Value::Array(
+            requests
+                .iter()
+                .map(|request| respond(&mut chain, request))
+                .collect(),
+        )),
+        request => Json(respond(&mut chain, &request)),
+    }
+}
+
+fn respond(chain: &mut FakeChain, request: &Value) -> Value {
+    let id = request["id"].clone();
+    let method = request["method"].as_str().unwrap_or_default();
+    let params = &request["params"];
+
+    chain.methods.push(method.to_string());
+
+    let result = match method {
+        "eth_chainId" => Ok(json!(format!("0x{:x}", chain.chain_id))),
+        "eth_blockNumber" => Ok(json!(format!("0x{:x}", chain.block_number))),
+        "eth_getBlockByNumber" => Ok(block(chain, &params[0])),
+        "eth_getTransactionCount" => {
+            let from: Address = serde_json::from_value(params[0].clone()).unwrap_or_default();
+            let nonce = chain.nonces.get(&from).copied().unwrap_or_default();
+            Ok(json!(format!("0x{:x}", nonce)))
+        }
+        "eth_getBalance" => {
+            let address: Address = serde_json::from_value(params[0].clone()).unwrap_or_default();
+            let balance = chain.balances.get(&address).copied().unwrap_or_default();
+            Ok(json!(balance))
+        }
+        "eth_estimateGas" => Ok(json!("0x30000")),
+        "eth_gasPrice" | "eth_maxPriorityFeePerGas" => Ok(json!("0x3b9aca00")),
+        "eth_feeHistory" => Ok(json!({
+            "oldestBlock": format!("0x{:x}", chain.block_number),
+            "baseFeePerGas": ["0x3b9aca00", "0x3b9aca00"],
+            "gasUsedRatio": [0.5],
+            "reward": [["0x3b9aca00"]],
+        })),
+        "eth_call" => call(chain, &params[0]),
+        "eth_sendRawTransaction" => send_raw_transaction(chain, &params[0]),
+        "eth_getTransactionReceipt" => Ok(Value::Null),
+        _ => Err(format!("method {} not supported", method)),
+    };
+
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(message) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32000, "message": message },
+        }),
+    }
+}
+
+fn block(chain: &FakeChain, number: &Value) -> Value {
+    let number = match number.as_str() {
+        Some(hex_number) if hex_number.starts_with("0x") => {
+            u64::from_str_radix(hex_number.trim_start_matches("0x"), 16).unwrap_or_default()
+        }
+        _ => chain.block_number,
+    };
+    if number > chain.block_number {
+        return Value::Null;
+    }
+
+    let mut block: Block = Block::default();
+    block.header.hash = keccak256(number.to_be_bytes());
+    block.header.inner.number = number;
+    block.header.inner.base_fee_per_gas = Some(1_000_000_000);
+    block.transactions =
+        BlockTransactions::Hashes(chain.blocks.get(&number).cloned().unwrap_or_default());
+
+    serde_json::to_value(block).unwrap()
+}
+
+fn call(chain: &FakeChain, tx: &Value) -> Result<Value, String> {
+    let to: Address = serde_json::from_value(tx["to"].clone()).map_err(|e| e.to_string())?;
+    let input = tx
+        .get("input")
+        .or_else(|| tx.get("data"))
+        .and_then(|input| input.as_str())
+        .and_then(|input| hex::decode(input).ok())
+        .unwrap_or_default();
+
+    if input.len() < 4 {
+        return Err("execution reverted".to_string());
+    }
+    let selector: [u8; 4] = input[..4].try_into().unwrap();
+
+    let output = if let Some(info) = chain.channels.get(&to) {
+        match selector {
+            PaymentChannelContract::getBalanceCall::SELECTOR => info.balance.abi_encode(),
+            PaymentChannelContract::expirationCall::SELECTOR => info.expiration.abi_encode(),
+            PaymentChannelContract::channelIdCall::SELECTOR => info.channel_id.abi_encode(),
+            PaymentChannelContract::senderCall::SELECTOR => info.sender.abi_encode(),
+            PaymentChannelContract::recipientCall::SELECTOR => info.recipient.abi_encode(),
+            PaymentChannelContract::priceCall::SELECTOR => info.price.abi_encode(),
+            PaymentChannelContract::tokenCall::SELECTOR => info.token.abi_encode(),
+            _ => return Err("execution reverted".to_string()),
+        }
+    } else if selector == ChannelFactoryContract::channelsCall::SELECTOR {
+        let call = ChannelFactoryContract::channelsCall::abi_decode(&input, true)
+            .map_err(|e| e.to_string())?;
+        chain
+            .factory
+            .get(&(to, call._0))
+            .copied()
+            .unwrap_or_default()
+            .abi_encode()
+    } else if selector == ChannelFactoryContract::pricingCall::SELECTOR {
+        let call = ChannelFactoryContract::pricingCall::abi_decode(&input, true)
+            .map_err(|e| e.to_string())?;
+        chain
+            .pricing
+            .get(&(to, call._0))
+            .copied()
+            .unwrap_or_default()
+            .abi_encode()
+    } else {
+        return Err("execution reverted".to_string());
+    };
+
+    Ok(json!(hex::encode_prefixed(output)))
+}
+
+fn send_raw_transaction(chain: &mut FakeChain, raw: &Value) -> Result<Value, String> {
+    let raw = raw
+        .as_str()
+        .and_then(|raw| hex::decode(raw).ok())
+        .ok_or("invalid transaction")?;
+
+    let envelope = TxEnvelope::decode_2718(&mut raw.as_slice()).map_err(|e| e.to_string())?;
+    let from = envelope.recover_signer().map_err(|e| e.to_string())?;
+    let to = envelope.to().ok_or("contract creation not supported")?;
+
+    if let Ok(register) = ChannelFactoryContract::registerCall::abi_decode(envelope.input(), true) {
+        chain.pricing.insert((to, from), register.price);
+    } else {
+        let close = PaymentChannelContract::closeCall::abi_decode(envelope.input(), true)
+            .map_err(|_| "execution reverted".to_string())?;
+
+        let info = chain
+            .channels
+            .get_mut(&to)
+            .ok_or("execution reverted".to_string())?;
+        if info.recipient != from {
+            return Err("execution reverted".to_string());
+        }
+
+        // As the contract does, the voucher has to be signed by the sender of the channel
+        let message = create_message(
+            info.channel_id,
+            close.channelBalance,
+            close.nonce,
+            &close.rawBody,
+        );
+        let signer = Signature::try_from(close.signature.as_ref())
+            .and_then(|signature| signature.recover_address_from_msg(message))
+            .map_err(|_| "execution reverted".to_string())?;
+        if signer != info.sender {
+            return Err("execution reverted".to_string());
+        }
+
+        // The contract pays out the whole balance, to the recipient and back to the sender
+        info.balance = U256::ZERO;
+
+        chain.closes.push(FakeClose {
+            from,
+            channel: to,
+            balance: close.channelBalance,
+            nonce: close.nonce,
+            raw_body: close.rawBody,
+            signature: close.signature,
+        });
+    }
+    *chain.nonces.entry(from).or_default() += 1;
+
+    // Mine the transaction right away
+    let hash = keccak256(&raw);
+    chain.block_number += 1;
+    let block_number = chain.block_number;
+    chain.blocks.insert(block_number, vec![hash]);
+
+    Ok(json!(hash))
+}
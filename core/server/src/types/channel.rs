@@ -1,10 +1,17 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use alloy::{
-    primitives::{Address, U256},
+    primitives::{Address, Bytes, U256},
     signers::Signature,
 };
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 
+use crate::{
+    error::AuthError,
+    types::{CanonicalRequest, MessageScheme, NonceWindow},
+};
+
 #[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PaymentChannel {
@@ -25,11 +32,243 @@ pub struct PaymentChannel {
     pub channel_id: U256,
 }
 
+// State of a channel as held by the channel contract
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    pub address: Address,
+    pub sender: Address,
+    pub recipient: Address,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub balance: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub expiration: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: U256,
+
+    #[serde_as(as = "DisplayFromStr")]
+    pub price: U256,
+
+    pub token: Address,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SignedRequest {
+    #[serde(default)]
+    pub scheme: MessageScheme,
     pub message: Vec<u8>,
     pub signature: Signature,
     pub payment_channel: PaymentChannel,
     pub payment_amount: U256,
     pub body_bytes: Vec<u8>,
+    #[serde(default)]
+    pub request: Option<CanonicalRequest>, // Set when the client signed the canonical request
+    #[serde(default)]
+    pub route: Option<String>, // `METHOD /path` of the request, for the audit log
+}
+
+impl SignedRequest {
+    // What the message was signed over, the canonical request if there is one or else the body
+    pub fn payload(&self) -> Vec<u8> {
+        match &self.request {
+            Some(request) => request.encode(&self.body_bytes),
+            None => self.body_bytes.clone(),
+        }
+    }
+}
+
+// A voucher signed by the sender, what the channel is closed with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelVoucher {
+    pub scheme: MessageScheme,
+    pub payment_channel: PaymentChannel, // As signed by the sender
+    pub message: Bytes,
+    pub signature: Signature,
+    pub raw_body: Bytes, // `rawBody` to close the channel with, see `ChannelState::settlement_body`
+}
+
+// Lifecycle of a channel on the server side
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelStatus {
+    PendingValidation, // First seen, being validated against the contract
+    Active,            // Validated, requests are served against it
+    Settling,          // Our close transaction is in flight
+    Closed,            // Closed or timed out onchain, nothing left to pay with
+    Expired,           // Past its expiration, until the sender extends it
+    Disputed,          // Local state disagrees with the chain, needs attention
+}
+
+impl ChannelStatus {
+    pub fn can_transition_to(self, next: ChannelStatus) -> bool {
+        use ChannelStatus::*;
+
+        matches!(
+            (self, next),
+            (PendingValidation, Active)
+                | (PendingValidation, Closed)
+                | (Active, Settling)
+                | (Active, Closed)
+                | (Active, Expired)
+                | (Active, Disputed)
+                | (Settling, Active) // Close transaction failed
+                | (Settling, Closed)
+                | (Expired, Active) // Extended onchain
+                | (Expired, Settling)
+                | (Expired, Closed)
+                | (Expired, Disputed)
+                | (Disputed, Active)
+                | (Disputed, Settling)
+                | (Disputed, Closed)
+        )
+    }
+
+    // Error returned to a request made against a channel in this status
+    pub fn ensure_active(self) -> Result<(), AuthError> {
+        match self {
+            ChannelStatus::Active => Ok(()),
+            ChannelStatus::Settling | ChannelStatus::Closed => Err(AuthError::ChannelClosed),
+            ChannelStatus::Expired => Err(AuthError::Expired),
+            ChannelStatus::PendingValidation | ChannelStatus::Disputed => {
+                Err(AuthError::ChannelUnavailable)
+            }
+        }
+    }
+}
+
+// Manual correction of a channel by an operator, the fields left out are kept
+#[serde_as]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelAdjustment {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deposited: Option<U256>,
+
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served: Option<U256>,
+
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<U256>,
+
+    // Set as is, the usual transitions don't apply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ChannelStatus>,
+}
+
+// Local record of a channel kept by the server, the latest state agreed with the sender along with what we know from the chain
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelRecord {
+    pub channel: PaymentChannel,
+
+    // Total amount deposited into the channel contract as last observed, used to credit top-ups exactly once
+    #[serde_as(as = "DisplayFromStr")]
+    pub deposited: U256,
+
+    pub status: ChannelStatus,
+
+    // Unix timestamp of the last status change
+    pub updated_at: u64,
+
+    // Total charged against the channel, what is left to the sender is `deposited - served`
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub served: U256,
+
+    // Lowest balance claimed by a voucher accepted since the last deposit, closing the channel with it pays out `deposited - lowest_claim`
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub lowest_claim: U256,
+
+    #[serde(default)]
+    pub nonces: NonceWindow,
+
+    // Settleable voucher with the lowest claimed balance, the one to close the channel with
+    #[serde(default)]
+    pub voucher: Option<ChannelVoucher>,
+}
+
+impl ChannelRecord {
+    pub fn new(channel: PaymentChannel, deposited: U256) -> Self {
+        Self {
+            channel,
+            deposited,
+            status: ChannelStatus::PendingValidation,
+            updated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            served: U256::ZERO,
+            lowest_claim: U256::MAX,
+            nonces: NonceWindow::default(),
+            voucher: None,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.status == ChannelStatus::Closed
+    }
+
+    // Credit a deposit seen onchain, `deposited` being the contract's new total. Returns the amount credited
+    // The vouchers so far claim a balance out of the previous deposit, closing with them would pay out the new deposit
+    // as well, so the kept voucher is dropped and the next one replaces it
+    pub fn credit_deposit(&mut self, deposited: U256) -> U256 {
+        if deposited <= self.deposited {
+            return U256::ZERO;
+        }
+
+        let credit = deposited - self.deposited;
+        self.deposited = deposited;
+        self.channel.balance += credit;
+        self.lowest_claim = U256::MAX;
+        self.voucher = None;
+        credit
+    }
+
+    // Apply an operator's correction, the balance left to the sender follows the deposit and what was served
+    pub fn adjust(&mut self, adjustment: &ChannelAdjustment) {
+        if let Some(deposited) = adjustment.deposited {
+            self.deposited = deposited;
+        }
+        if let Some(served) = adjustment.served {
+            self.served = served;
+        }
+        if let Some(expiration) = adjustment.expiration {
+            self.channel.expiration = expiration;
+        }
+        if let Some(status) = adjustment.status {
+            self.status = status;
+        }
+        self.channel.balance = self.deposited.saturating_sub(self.served);
+        self.updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+    }
+
+    // Move the channel to the next status, returns the previous one
+    pub fn transition(&mut self, next: ChannelStatus) -> Result<ChannelStatus, AuthError> {
+        if !self.status.can_transition_to(next) {
+            println!(
+                "Failed: Channel {} can't go from {:?} to {:?}",
+                self.channel.channel_id, self.status, next
+            );
+            return Err(AuthError::InvalidTransition(self.status, next));
+        }
+
+        let previous = self.status;
+        self.status = next;
+        self.updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        Ok(previous)
+    }
 }
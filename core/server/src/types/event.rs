@@ -0,0 +1,49 @@
+use alloy::primitives::{Address, U256};
+use serde::{Deserialize, Serialize};
+
+// Events emitted by the payment channel contract that affect the local channel state
+// `address` is the channel contract that emitted the event
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelEvent {
+    Deposit {
+        channel_id: U256,
+        address: Address,
+        amount: U256,
+        new_balance: U256,
+    },
+    ExpirationExtended {
+        channel_id: U256,
+        address: Address,
+        expiration: U256,
+    },
+    Closed {
+        channel_id: U256,
+        address: Address,
+        amount: U256,
+        nonce: U256,
+    },
+    TimeoutClaimed {
+        channel_id: U256,
+        address: Address,
+    },
+}
+
+impl ChannelEvent {
+    pub fn channel_id(&self) -> U256 {
+        match self {
+            ChannelEvent::Deposit { channel_id, .. }
+            | ChannelEvent::ExpirationExtended { channel_id, .. }
+            | ChannelEvent::Closed { channel_id, .. }
+            | ChannelEvent::TimeoutClaimed { channel_id, .. } => *channel_id,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        match self {
+            ChannelEvent::Deposit { address, .. }
+            | ChannelEvent::ExpirationExtended { address, .. }
+            | ChannelEvent::Closed { address, .. }
+            | ChannelEvent::TimeoutClaimed { address, .. } => *address,
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AuthError;
+
+// Format of the message the sender signs for each request, selected with the `X-Message-Version` header
+// Requests without the header use the legacy format
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageScheme {
+    // 1: keccak256(abi.encodePacked(channelId, balance, nonce, body)) signed as a personal message, the one the channel contract verifies on close
+    #[default]
+    Legacy,
+    // 2: EIP-712 typed `Voucher`, bound to the chain and the channel contract
+    Eip712,
+    // 3: the legacy message over a body prefixed with the chain id, the factory and the channel contract
+    // Still settleable by the channel contract, with the prefixed body as `rawBody`
+    Domain,
+    // 4: the legacy message over keccak256(body), settled with the 32 bytes hash as `rawBody` instead of the whole body
+    BodyHash,
+}
+
+impl MessageScheme {
+    pub fn version(self) -> u8 {
+        match self {
+            MessageScheme::Legacy => 1,
+            MessageScheme::Eip712 => 2,
+            MessageScheme::Domain => 3,
+            MessageScheme::BodyHash => 4,
+        }
+    }
+
+    // The channel contract only verifies personal messages, EIP-712 vouchers can't close a channel
+    pub fn is_settleable(self) -> bool {
+        self != MessageScheme::Eip712
+    }
+}
+
+impl FromStr for MessageScheme {
+    type Err = AuthError;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version.trim() {
+            "1" => Ok(MessageScheme::Legacy),
+            "2" => Ok(MessageScheme::Eip712),
+            "3" => Ok(MessageScheme::Domain),
+            "4" => Ok(MessageScheme::BodyHash),
+            _ => Err(AuthError::InvalidMessage),
+        }
+    }
+}
+
+impl fmt::Display for MessageScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.version())
+    }
+}
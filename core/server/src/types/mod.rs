@@ -1,3 +1,14 @@
 pub mod channel;
+pub mod event;
+pub mod message;
+pub mod nonce;
+pub mod request;
 
-pub use channel::{PaymentChannel, SignedRequest};
+pub use channel::{
+    ChannelAdjustment, ChannelInfo, ChannelRecord, ChannelStatus, ChannelVoucher, PaymentChannel,
+    SignedRequest,
+};
+pub use event::ChannelEvent;
+pub use message::MessageScheme;
+pub use nonce::{NonceWindow, NONCE_WINDOW};
+pub use request::CanonicalRequest;
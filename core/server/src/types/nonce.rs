@@ -0,0 +1,62 @@
+use alloy::primitives::U256;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::error::AuthError;
+
+// How far behind the highest nonce a request can still arrive
+pub const NONCE_WINDOW: u64 = 128;
+
+// Nonces recently used on a channel, so that concurrent requests can arrive out of order
+// Bit i of the bitmap is set once `highest - i` was used, nonces older than the window are refused
+#[serde_as]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NonceWindow {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    highest: Option<U256>,
+    bitmap: u128,
+}
+
+impl NonceWindow {
+    pub fn highest(&self) -> Option<U256> {
+        self.highest
+    }
+
+    // Fails with `InvalidNonce` if the nonce was already used or is too old
+    pub fn check(&self, nonce: U256) -> Result<(), AuthError> {
+        let Some(highest) = self.highest else {
+            return Ok(());
+        };
+        if nonce > highest {
+            return Ok(());
+        }
+
+        let offset = highest - nonce;
+        if offset >= U256::from(NONCE_WINDOW) || self.bitmap & (1 << offset.to::<u64>()) != 0 {
+            return Err(AuthError::InvalidNonce);
+        }
+
+        Ok(())
+    }
+
+    // Record the nonce as used, once `check` passed
+    pub fn insert(&mut self, nonce: U256) {
+        let Some(highest) = self.highest.filter(|highest| nonce <= *highest) else {
+            // Slide the window up to the new highest nonce
+            let shift = self.highest.map(|highest| nonce - highest);
+            self.bitmap = match shift {
+                Some(shift) if shift < U256::from(NONCE_WINDOW) => {
+                    (self.bitmap << shift.to::<u64>()) | 1
+                }
+                _ => 1,
+            };
+            self.highest = Some(nonce);
+            return;
+        };
+
+        let offset = highest - nonce;
+        if offset < U256::from(NONCE_WINDOW) {
+            self.bitmap |= 1 << offset.to::<u64>();
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use axum::http::{HeaderMap, Method, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::error::AuthError;
+
+// Canonical form of the HTTP request, signed in place of the bare body when the client sends `X-Signed-Headers`
+// Binds the voucher to the method, the url, the timestamp and the headers the client picked, so it can't be replayed against another endpoint
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanonicalRequest {
+    pub method: String,                 // Uppercase
+    pub path: String,                   // As sent, `/` if empty
+    pub query: String,                  // Parameters sorted by name then value, as sent otherwise
+    pub headers: Vec<(String, String)>, // Lowercase names and trimmed values, sorted by name
+    pub timestamp: u64,                 // `X-Timestamp`
+}
+
+impl CanonicalRequest {
+    pub fn new(
+        method: &str,
+        path: &str,
+        query: &str,
+        headers: Vec<(String, String)>,
+        timestamp: u64,
+    ) -> Self {
+        let mut params: Vec<(&str, &str)> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| param.split_once('=').unwrap_or((param, "")))
+            .collect();
+        params.sort();
+
+        let mut headers: Vec<(String, String)> = headers
+            .into_iter()
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+            .collect();
+        headers.sort();
+
+        Self {
+            method: method.to_uppercase(),
+            path: if path.is_empty() { "/" } else { path }.to_string(),
+            query: params
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("&"),
+            headers,
+            timestamp,
+        }
+    }
+
+    // Build it from the incoming request, `signed_headers` being the value of `X-Signed-Headers`, comma separated header names
+    pub fn from_parts(
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        signed_headers: &str,
+        timestamp: u64,
+    ) -> Result<Self, AuthError> {
+        let mut signed = Vec::new();
+        for name in signed_headers
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            // A header the client signed but didn't send can't be checked
+            let value = headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(AuthError::InvalidMessage)?;
+            signed.push((name.to_string(), value.to_string()));
+        }
+
+        Ok(Self::new(
+            method.as_str(),
+            uri.path(),
+            uri.query().unwrap_or_default(),
+            signed,
+            timestamp,
+        ))
+    }
+
+    // The bytes signed in place of the body
+    // One line per field, one per header as `name:value`, then the body as is
+    pub fn encode(&self, body: &[u8]) -> Vec<u8> {
+        let mut encoded = format!("{}\n{}\n{}\n", self.method, self.path, self.query);
+        for (name, value) in &self.headers {
+            encoded.push_str(&format!("{}:{}\n", name, value));
+        }
+        encoded.push_str(&format!("{}\n", self.timestamp));
+
+        let mut encoded = encoded.into_bytes();
+        encoded.extend_from_slice(body);
+        encoded
+    }
+}
@@ -1,8 +1,13 @@
 use alloy::{
     dyn_abi::DynSolValue,
-    primitives::{keccak256, U256},
+    primitives::{keccak256, Address, U256},
 };
 
+use crate::types::PaymentChannel;
+
+// Prefix of the domain separated body, so it can't be mistaken for a plain request body
+pub const DOMAIN_TAG: &[u8] = b"PipeGate";
+
 pub fn create_message(channel_id: U256, balance: U256, nonce: U256, body: &[u8]) -> Vec<u8> {
     let message = DynSolValue::Tuple(vec![
         DynSolValue::Uint(channel_id, 256),
@@ -17,3 +22,48 @@ pub fn create_message(channel_id: U256, balance: U256, nonce: U256, body: &[u8])
 
     hashed_message.to_vec()
 }
+
+// Same as `create_message`, over the hash of the body instead of the body
+// The contract then only needs the 32 bytes hash as `rawBody` to close the channel, whatever the size of the request
+pub fn create_body_hash_message(
+    channel_id: U256,
+    balance: U256,
+    nonce: U256,
+    body: &[u8],
+) -> Vec<u8> {
+    create_message(channel_id, balance, nonce, keccak256(body).as_slice())
+}
+
+// Body committing to the chain, the factory and the channel contract on top of the request body
+// The contract verifies the packed message over whatever raw body it is given, so this is also the `rawBody` to close the channel with
+pub fn create_domain_body(
+    chain_id: u64,
+    factory: Address,
+    channel_address: Address,
+    body: &[u8],
+) -> Vec<u8> {
+    let domain_body = DynSolValue::Tuple(vec![
+        DynSolValue::Bytes(DOMAIN_TAG.to_vec()),
+        DynSolValue::Uint(U256::from(chain_id), 256),
+        DynSolValue::Address(factory),
+        DynSolValue::Address(channel_address),
+        DynSolValue::Bytes(body.to_vec()),
+    ]);
+
+    domain_body.abi_encode_packed()
+}
+
+// Same as `create_message`, over the domain separated body
+pub fn create_domain_message(
+    chain_id: u64,
+    factory: Address,
+    payment_channel: &PaymentChannel,
+    body: &[u8],
+) -> Vec<u8> {
+    create_message(
+        payment_channel.channel_id,
+        payment_channel.balance,
+        payment_channel.nonce,
+        &create_domain_body(chain_id, factory, payment_channel.address, body),
+    )
+}
@@ -1,17 +1,42 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use alloy::{hex, primitives::U256};
+use alloy::{
+    hex,
+    primitives::{Bytes, U256},
+};
+use tokio::sync::RwLockWriteGuard;
 
 use crate::{
+    audit::Charge,
     channel::ChannelState,
+    config::Settings,
     error::AuthError,
-    types::{PaymentChannel, SignedRequest},
-    utils::create_message,
+    types::{
+        ChannelRecord, ChannelStatus, ChannelVoucher, MessageScheme, PaymentChannel, SignedRequest,
+        NONCE_WINDOW,
+    },
+    utils::{create_body_hash_message, create_domain_message, create_message},
+    voucher::create_typed_message,
 };
 
+// How long a request waits for a concurrent first request to validate the channel
+const VALIDATION_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub async fn verify_and_update_channel(
     state: &ChannelState,
-    mut request: SignedRequest,
+    request: SignedRequest,
+) -> Result<PaymentChannel, AuthError> {
+    verify_with_settings(state, &state.settings(), request).await
+}
+
+// With the settings the request started with, see `ChannelState::settings`
+pub(crate) async fn verify_with_settings(
+    state: &ChannelState,
+    settings: &Settings,
+    request: SignedRequest,
 ) -> Result<PaymentChannel, AuthError> {
     println!("\n=== verify_and_update_channel ===");
     println!("Payment amount: {}", request.payment_amount);
@@ -24,18 +49,59 @@ pub async fn verify_and_update_channel(
     println!("Message length: {}", request.message.len());
     println!("Original message: 0x{}", hex::encode(&request.message));
 
+    if state.is_blocked(request.payment_channel.sender) {
+        println!("Failed: Sender {} blocked", request.payment_channel.sender);
+        return Err(AuthError::SenderNotAllowed);
+    }
+
     // Check for rate limiting
     state
-        .check_rate_limit(request.payment_channel.sender)
+        .check_rate_limit(request.payment_channel.sender, settings)
         .await?;
 
+    if request.request.is_none() && state.requires_request_binding() {
+        println!("Failed: Request not signed");
+        return Err(AuthError::InvalidMessage);
+    }
+
     // Verify that the message matches what we expect
-    let reconstructed_message = create_message(
-        request.payment_channel.channel_id,
-        request.payment_channel.balance,
-        request.payment_channel.nonce,
-        &request.body_bytes,
-    );
+    let payload = request.payload();
+    let reconstructed_message = match request.scheme {
+        MessageScheme::Legacy if !state.accepts_legacy_messages() => {
+            println!("Failed: Legacy messages not accepted");
+            return Err(AuthError::InvalidMessage);
+        }
+        MessageScheme::Legacy => create_message(
+            request.payment_channel.channel_id,
+            request.payment_channel.balance,
+            request.payment_channel.nonce,
+            &payload,
+        ),
+        MessageScheme::Eip712 if !state.accepts_typed_vouchers() => {
+            println!("Failed: Typed vouchers not accepted");
+            return Err(AuthError::InvalidMessage);
+        }
+        MessageScheme::Eip712 => {
+            create_typed_message(state.chain_id().await?, &request.payment_channel, &payload)
+        }
+        MessageScheme::BodyHash => create_body_hash_message(
+            request.payment_channel.channel_id,
+            request.payment_channel.balance,
+            request.payment_channel.nonce,
+            &payload,
+        ),
+        // Bound to the factory's deployment, there is none to bind to without one
+        MessageScheme::Domain if state.factory().is_none() => {
+            println!("Failed: Domain messages need a factory");
+            return Err(AuthError::InvalidMessage);
+        }
+        MessageScheme::Domain => create_domain_message(
+            state.chain_id().await?,
+            state.factory().unwrap_or_default(),
+            &request.payment_channel,
+            &payload,
+        ),
+    };
 
     if request.message != reconstructed_message {
         println!("Failed: Message mismatch");
@@ -50,11 +116,10 @@ pub async fn verify_and_update_channel(
             &request.payment_channel,
             &request.signature,
             &request.message,
+            request.scheme,
         )
         .await?;
 
-    let mut channels = state.channels.write().await;
-
     // Check if the channel is not expired with the current timestamp
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -65,55 +130,262 @@ pub async fn verify_and_update_channel(
         return Err(AuthError::Expired);
     }
 
-    // Check if channel exists
-    // NOTE: Nonce validation can be skipped as the balance will be acting as nonce here, the sender will always send the tx with the highest balance, we'll check for that here within our local record
-    if let Some(existing_channel) = channels.get(&request.payment_channel.channel_id) {
-        println!("Existing channel found");
-        // Ensure new nonce is greater than existing nonce
-        if request.payment_channel.nonce <= existing_channel.nonce {
-            println!(
-                "Failed: Invalid nonce - current: {}, received: {}",
-                existing_channel.nonce, request.payment_channel.nonce
-            );
-            return Err(AuthError::InvalidNonce);
-        } else {
-            println!("Nonce match");
+    // Kept with the channel, it's what the channel gets closed with
+    let raw_body = match request.scheme {
+        // Not settleable by the channel contract, never kept as the channel's voucher
+        MessageScheme::Eip712 => Bytes::from(payload),
+        scheme => {
+            state
+                .settlement_body(scheme, &request.payment_channel, &payload)
+                .await?
+        }
+    };
+    let payment_amount = request.payment_amount;
+    let charge = Charge {
+        channel_id: request.payment_channel.channel_id,
+        sender: request.payment_channel.sender,
+        nonce: request.payment_channel.nonce,
+        amount: payment_amount,
+        route: request.route,
+        signature: request.signature,
+    };
+    let voucher = ChannelVoucher {
+        scheme: request.scheme,
+        payment_channel: request.payment_channel,
+        message: request.message.into(),
+        signature: request.signature,
+        raw_body,
+    };
+
+    let channel_id = voucher.payment_channel.channel_id;
+
+    let payment_channel = charge_channel(state, channel_id, voucher, payment_amount, now).await?;
+
+    if let Some(audit_log) = state.audit_log() {
+        if let Err(e) = audit_log.append(charge) {
+            // The request is already paid for, it's served anyway
+            println!("Failed: Audit log write - Error {}", e);
         }
+    }
+
+    Ok(payment_channel)
+}
 
-        if request.payment_channel.balance != existing_channel.balance {
-            println!(
-                "Failed: Invalid balance - current: {}, received: {}",
-                existing_channel.balance, request.payment_channel.balance
+async fn charge_channel(
+    state: &ChannelState,
+    channel_id: U256,
+    voucher: ChannelVoucher,
+    payment_amount: U256,
+    now: u64,
+) -> Result<PaymentChannel, AuthError> {
+    loop {
+        let mut channels = state.channels.write().await;
+
+        // Check if channel exists
+        let Some(existing) = channels.get_mut(&channel_id) else {
+            println!("New channel found");
+
+            // Reserve the channel while it's validated, so that the lock isn't held during the onchain calls
+            channels.insert(
+                channel_id,
+                ChannelRecord::new(voucher.payment_channel.clone(), U256::ZERO),
             );
-            return Err(AuthError::InvalidChannel);
-        } else {
-            println!("Balance match");
+            drop(channels);
+
+            return open_channel(state, voucher, payment_amount).await;
+        };
+
+        if existing.status == ChannelStatus::PendingValidation {
+            // A concurrent first request is validating the channel, wait for it instead of failing
+            let validation = state.validation(channel_id);
+            let validated = validation.notified();
+            tokio::pin!(validated);
+            validated.as_mut().enable();
+            drop(channels);
+
+            if tokio::time::timeout(VALIDATION_TIMEOUT, validated)
+                .await
+                .is_err()
+            {
+                return Err(AuthError::ChannelUnavailable);
+            }
+            continue;
         }
-    } else {
-        println!("New channel found");
 
-        // Verify that the channel contract data is correct
-        // 1. Verify the balance is available in the contract as the channel balance
-        // 2. Verify the expiration is in the future
-        // 3. Verify the channel ID is correct
-        state.validate_channel(&request.payment_channel).await?;
+        println!("Existing channel found");
+        return update_channel(state, channels, voucher, payment_amount, now).await;
+    }
+}
+
+// First request on the channel, validate it against the contract before serving it
+async fn open_channel(
+    state: &ChannelState,
+    voucher: ChannelVoucher,
+    payment_amount: U256,
+) -> Result<PaymentChannel, AuthError> {
+    let channel_id = voucher.payment_channel.channel_id;
+
+    // Verify that the channel contract data is correct
+    // 1. Verify the balance is available in the contract as the channel balance
+    // 2. Verify the expiration is in the future
+    // 3. Verify the channel ID is correct
+    let validation = match state.validate_channel(&voucher.payment_channel).await {
+        // Ensure the nonce is within the first window, the first requests can arrive out of order
+        Ok(_) if voucher.payment_channel.nonce >= U256::from(NONCE_WINDOW) => {
+            Err(AuthError::InvalidNonce)
+        }
+        result => result,
+    };
+
+    let mut channels = state.channels.write().await;
+    let result = match channels.get_mut(&channel_id) {
+        Some(record) if record.status == ChannelStatus::PendingValidation => match validation {
+            Ok(onchain_balance) => {
+                record.deposited = onchain_balance;
+                record.transition(ChannelStatus::Active)?;
+
+                charge(record, voucher, payment_amount)
+            }
+            Err(e) => {
+                channels.remove(&channel_id);
+                Err(e)
+            }
+        },
+        // Closed by an onchain event in the meantime
+        Some(record) => Err(record
+            .status
+            .ensure_active()
+            .err()
+            .unwrap_or(AuthError::ChannelUnavailable)),
+        None => Err(AuthError::ChannelNotFound),
+    };
+
+    // Let the requests waiting on the validation through
+    state.validated(channel_id);
+
+    result
+}
+
+// Request on a channel already validated
+// A claimed top-up or extension is checked against the contract without holding the lock, other channels keep being served
+async fn update_channel(
+    state: &ChannelState,
+    mut channels: RwLockWriteGuard<'_, HashMap<U256, ChannelRecord>>,
+    voucher: ChannelVoucher,
+    payment_amount: U256,
+    now: u64,
+) -> Result<PaymentChannel, AuthError> {
+    let channel_id = voucher.payment_channel.channel_id;
+    let existing = channels
+        .get_mut(&channel_id)
+        .ok_or(AuthError::ChannelNotFound)?;
+    check_update(existing, &voucher)?;
+
+    // The sender can only claim more than we know was deposited after a deposit or an extension onchain
+    if voucher.payment_channel.balance <= existing.deposited
+        && voucher.payment_channel.expiration <= existing.channel.expiration
+    {
+        if existing.channel.expiration < U256::from(now) {
+            existing.transition(ChannelStatus::Expired)?;
+            return Err(AuthError::Expired);
+        }
+        return charge(existing, voucher, payment_amount);
+    }
+
+    println!("Channel top-up or extension claimed, re-validating");
+    drop(channels);
+    let onchain_balance = state.validate_channel(&voucher.payment_channel).await?;
+
+    // The channel may have been closed, or the nonce used, in the meantime
+    let mut channels = state.channels.write().await;
+    let existing = channels
+        .get_mut(&channel_id)
+        .ok_or(AuthError::ChannelNotFound)?;
+    check_update(existing, &voucher)?;
+
+    // The channel balance is derived from the deposits, what was already spent stays spent
+    existing.credit_deposit(onchain_balance);
+    if existing.status == ChannelStatus::Expired {
+        existing.transition(ChannelStatus::Active)?;
+    }
+
+    charge(existing, voucher, payment_amount)
+}
 
-        // Ensure the nonce is 0
-        if request.payment_channel.nonce != U256::from(0) {
-            return Err(AuthError::InvalidNonce);
+// Whether the voucher can be charged against the channel as it stands
+fn check_update(existing: &ChannelRecord, voucher: &ChannelVoucher) -> Result<(), AuthError> {
+    match existing.status {
+        ChannelStatus::Active => {}
+        // The sender claims the expiration was extended, checked against the contract
+        ChannelStatus::Expired
+            if voucher.payment_channel.expiration > existing.channel.expiration => {}
+        status => {
+            println!("Failed: Channel is {:?}", status);
+            return Err(status.ensure_active().unwrap_err());
         }
     }
 
+    // Ensure the nonce wasn't used yet, requests can arrive out of order within the window
+    if let Err(e) = existing.nonces.check(voucher.payment_channel.nonce) {
+        println!(
+            "Failed: Invalid nonce - highest: {:?}, received: {}",
+            existing.nonces.highest(),
+            voucher.payment_channel.nonce
+        );
+        return Err(e);
+    }
+    println!("Nonce match");
+
+    Ok(())
+}
+
+// Deduct the payment from the channel and record the voucher
+// Vouchers can arrive out of order, so the balance is derived from the total served rather than from the latest voucher,
+// and a voucher is only accepted if, together with the ones before, it lets us claim everything served so far
+fn charge(
+    record: &mut ChannelRecord,
+    voucher: ChannelVoucher,
+    payment_amount: U256,
+) -> Result<PaymentChannel, AuthError> {
+    let payment_channel = voucher.payment_channel.clone();
+
+    let remaining = record.deposited.saturating_sub(record.served);
+    if payment_channel.balance < payment_amount || remaining < payment_amount {
+        println!("Failed: Insufficient balance");
+        return Err(AuthError::InsufficientBalance);
+    }
+
+    let lowest_claim = record.lowest_claim.min(payment_channel.balance);
+    if record.deposited.saturating_sub(lowest_claim) < record.served {
+        println!(
+            "Failed: Voucher doesn't cover what was served - claimed: {}, served: {}",
+            payment_channel.balance, record.served
+        );
+        return Err(AuthError::InsufficientBalance);
+    }
+
     // NOTE: Update Balance for updating the local state, deducting the balance from the channel
     println!("Updating channel state");
-    request.payment_channel.balance -= request.payment_amount;
+    record.nonces.insert(payment_channel.nonce);
+    record.served += payment_amount;
+    // Only a voucher the contract can verify is worth keeping, the last settleable one stays otherwise
+    let lower = record
+        .voucher
+        .as_ref()
+        .is_none_or(|kept| payment_channel.balance < kept.payment_channel.balance);
+    if voucher.scheme.is_settleable() && lower {
+        record.voucher = Some(voucher);
+    }
+    record.lowest_claim = lowest_claim;
 
-    // Update or insert the channel
-    channels.insert(
-        request.payment_channel.channel_id,
-        request.payment_channel.clone(),
-    );
+    record.channel = PaymentChannel {
+        balance: record.deposited - record.served,
+        nonce: record.nonces.highest().unwrap_or(payment_channel.nonce),
+        // Keep the latest expiration we know of, the sender's copy can lag behind an extension seen by the indexer
+        expiration: payment_channel.expiration.max(record.channel.expiration),
+        ..payment_channel
+    };
 
     println!("API request authorized");
-    Ok(request.payment_channel.clone())
+    Ok(record.channel.clone())
 }
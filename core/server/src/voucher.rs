@@ -0,0 +1,73 @@
+// EIP-712 typed vouchers
+// Wallets display the voucher fields instead of an opaque hash, and the domain binds it to the chain and the channel contract
+
+use alloy::{
+    primitives::{keccak256, Address, U256},
+    signers::{Signature, SignerSync},
+    sol,
+    sol_types::{eip712_domain, Eip712Domain, SolStruct},
+};
+
+use crate::{error::AuthError, types::PaymentChannel};
+
+sol! {
+    #[derive(Debug, PartialEq, Eq)]
+    struct Voucher {
+        uint256 channelId;
+        uint256 balance;
+        uint256 nonce;
+        bytes32 bodyHash;
+    }
+}
+
+pub fn voucher_domain(chain_id: u64, channel_address: Address) -> Eip712Domain {
+    eip712_domain! {
+        name: "PipeGate",
+        version: "1",
+        chain_id: chain_id,
+        verifying_contract: channel_address,
+    }
+}
+
+pub fn create_voucher(channel_id: U256, balance: U256, nonce: U256, body: &[u8]) -> Voucher {
+    Voucher {
+        channelId: channel_id,
+        balance,
+        nonce,
+        bodyHash: keccak256(body),
+    }
+}
+
+// The EIP-712 signing hash of the voucher for the request, what the sender signs
+pub fn create_typed_message(
+    chain_id: u64,
+    payment_channel: &PaymentChannel,
+    body: &[u8],
+) -> Vec<u8> {
+    let voucher = create_voucher(
+        payment_channel.channel_id,
+        payment_channel.balance,
+        payment_channel.nonce,
+        body,
+    );
+
+    voucher
+        .eip712_signing_hash(&voucher_domain(chain_id, payment_channel.address))
+        .to_vec()
+}
+
+// Sign the voucher for the request, the client side counterpart of the verification
+pub fn sign_voucher<S: SignerSync>(
+    signer: &S,
+    chain_id: u64,
+    payment_channel: &PaymentChannel,
+    body: &[u8],
+) -> Result<Signature, AuthError> {
+    let hash = create_typed_message(chain_id, payment_channel, body);
+
+    let signature = signer
+        .sign_hash_sync(&hash.as_slice().try_into().unwrap())
+        .map_err(|_| AuthError::InvalidSignature)?;
+
+    Signature::try_from(signature.as_bytes().as_slice()).map_err(|_| AuthError::InvalidSignature)
+}
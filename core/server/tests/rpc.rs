@@ -0,0 +1,1443 @@
+// End-to-end tests of the middleware over the real alloy code paths, against the local fake node
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    hex,
+    primitives::{Address, Bytes, U256},
+    signers::{local::PrivateKeySigner, Signature, SignerSync},
+    transports::http::reqwest,
+};
+use axum::{
+    routing::{get, post},
+    Json, Router,
+};
+use clap::Parser;
+use pipegate::{
+    access::{sign_internal_token, AccessPolicy, INTERNAL_HEADER},
+    admin::{Admin, ChannelSummary, SenderHistory, Settlement},
+    audit::AuditLog,
+    channel::ChannelState,
+    cli::{doctor, inspect, read_voucher, register, settle, CheckStatus, Cli, Context},
+    config::PipegateConfig,
+    error::{AuthError, CliError},
+    extract::{Paid, PaymentContext, PerRequest},
+    gateway::{gateway_router, GatewayConfig, SENDER_HEADER},
+    middleware::auth_middleware,
+    receipt::Receipt,
+    resync::{create_resync_message, ChannelSnapshot, RESYNC_PATH},
+    testing::FakeRpc,
+    types::{
+        CanonicalRequest, ChannelInfo, ChannelStatus, MessageScheme, PaymentChannel, SignedRequest,
+    },
+    utils::{create_body_hash_message, create_message},
+    verify::verify_and_update_channel,
+};
+
+const PRICE: u64 = 1000;
+const DEPOSIT: u64 = 1_000_000;
+
+struct Setup {
+    rpc: FakeRpc,
+    sender: PrivateKeySigner,
+    recipient: PrivateKeySigner,
+    channel: PaymentChannel,
+}
+
+async fn setup() -> Setup {
+    let rpc = FakeRpc::spawn(84532).await;
+    let sender = PrivateKeySigner::random();
+    let recipient = PrivateKeySigner::random();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let channel = PaymentChannel {
+        address: Address::repeat_byte(1),
+        sender: sender.address(),
+        recipient: recipient.address(),
+        balance: U256::from(DEPOSIT),
+        nonce: U256::ZERO,
+        expiration: U256::from(now + 3600),
+        channel_id: U256::from(1),
+    };
+
+    rpc.set_channel(ChannelInfo {
+        address: channel.address,
+        sender: channel.sender,
+        recipient: channel.recipient,
+        balance: channel.balance,
+        expiration: channel.expiration,
+        channel_id: channel.channel_id,
+        price: U256::from(PRICE),
+        token: Address::repeat_byte(3),
+    });
+
+    Setup {
+        rpc,
+        sender,
+        recipient,
+        channel,
+    }
+}
+
+fn sign(signer: &PrivateKeySigner, channel: &PaymentChannel, body: &[u8]) -> SignedRequest {
+    let message = create_message(channel.channel_id, channel.balance, channel.nonce, body);
+    let signature = signer.sign_message_sync(&message).unwrap();
+
+    SignedRequest {
+        scheme: MessageScheme::Legacy,
+        message,
+        signature: Signature::try_from(signature.as_bytes().as_slice()).unwrap(),
+        payment_channel: channel.clone(),
+        payment_amount: U256::from(PRICE),
+        body_bytes: body.to_vec(),
+        request: None,
+        route: None,
+    }
+}
+
+// Serve `app` on a local port
+async fn serve(app: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    address
+}
+
+// Attach the payment headers, with a voucher from `sender` over `body`
+fn paid(
+    request: reqwest::RequestBuilder,
+    sender: &PrivateKeySigner,
+    channel: &PaymentChannel,
+    body: &[u8],
+) -> reqwest::RequestBuilder {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    paid_at(request, sender, channel, body, timestamp)
+}
+
+// Same, at `timestamp`, for vouchers over a canonical request made at that time
+fn paid_at(
+    request: reqwest::RequestBuilder,
+    sender: &PrivateKeySigner,
+    channel: &PaymentChannel,
+    body: &[u8],
+    timestamp: u64,
+) -> reqwest::RequestBuilder {
+    let signed = sign(sender, channel, body);
+    request
+        .header("X-Message", hex::encode_prefixed(&signed.message))
+        .header(
+            "X-Signature",
+            hex::encode_prefixed(signed.signature.as_bytes()),
+        )
+        .header("X-Timestamp", timestamp.to_string())
+        .header("X-Payment", serde_json::to_string(channel).unwrap())
+}
+
+#[tokio::test]
+async fn validates_channel_over_rpc() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let factory = Address::repeat_byte(9);
+    let state = ChannelState::new(rpc.url()).with_factory(factory);
+
+    // Not created by the factory
+    let result = verify_and_update_channel(&state, sign(&sender, &channel, b"")).await;
+    assert!(matches!(result, Err(AuthError::InvalidChannel)));
+
+    rpc.register_channel(factory, channel.channel_id, channel.address);
+    let updated = verify_and_update_channel(&state, sign(&sender, &channel, b""))
+        .await
+        .unwrap();
+
+    assert_eq!(updated.balance, U256::from(DEPOSIT - PRICE));
+    assert!(rpc.methods().iter().any(|method| method == "eth_call"));
+}
+
+#[tokio::test]
+async fn rejects_contract_mismatch_over_rpc() {
+    let Setup {
+        rpc,
+        sender,
+        mut channel,
+        ..
+    } = setup().await;
+    let state = ChannelState::new(rpc.url());
+
+    channel.recipient = Address::repeat_byte(7);
+    let result = verify_and_update_channel(&state, sign(&sender, &channel, b"")).await;
+    assert!(matches!(result, Err(AuthError::InvalidChannel)));
+
+    // No contract at the address at all
+    channel.address = Address::repeat_byte(8);
+    let result = verify_and_update_channel(&state, sign(&sender, &channel, b"")).await;
+    assert!(matches!(result, Err(AuthError::ContractError(_))));
+}
+
+#[tokio::test]
+async fn middleware_serves_paid_requests() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let state = ChannelState::new(rpc.url());
+
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = state.clone();
+            auth_middleware(state, U256::from(PRICE), req, next)
+        }));
+
+    let url = format!("http://{}/", serve(app).await);
+
+    let client = reqwest::Client::new();
+    let response = paid(client.get(&url), &sender, &channel, b"")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let payment: PaymentChannel =
+        serde_json::from_str(response.headers()["X-Payment"].to_str().unwrap()).unwrap();
+    assert_eq!(payment.balance, U256::from(DEPOSIT - PRICE));
+    assert_eq!(response.text().await.unwrap(), "Hello, World!");
+
+    // Signed over the canonical request, the host header and the query
+    let next = PaymentChannel {
+        nonce: payment.nonce + U256::from(1),
+        ..payment
+    };
+    let host = url.trim_start_matches("http://").trim_end_matches('/');
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let canonical = CanonicalRequest::new(
+        "GET",
+        "/",
+        "b=2&a=1",
+        vec![("host".to_string(), host.to_string())],
+        timestamp,
+    );
+
+    let bound = |query: &str| {
+        let request = client.get(format!("{}?{}", url, query));
+        paid_at(request, &sender, &next, &canonical.encode(b""), timestamp)
+            .header("X-Signed-Headers", "host")
+    };
+
+    let response = bound("a=2&b=1").send().await.unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = bound("a=1&b=2").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Stamped too far in the future
+    let ahead = paid_at(client.get(&url), &sender, &next, b"", timestamp + 3600);
+    assert_eq!(ahead.send().await.unwrap().status(), 408);
+
+    // Without payment
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn retries_with_idempotency_key_are_charged_once() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let state = ChannelState::new(rpc.url()).with_idempotency(Duration::from_secs(60), true);
+
+    let served = Arc::new(AtomicUsize::new(0));
+    let handler_served = served.clone();
+    let handler_state = state.clone();
+    let app =
+        Router::new()
+            .route(
+                "/",
+                get(move || async move {
+                    (handler_served.fetch_add(1, Ordering::SeqCst) + 1).to_string()
+                }),
+            )
+            .layer(axum::middleware::from_fn(move |req, next| {
+                let state = handler_state.clone();
+                auth_middleware(state, U256::from(PRICE), req, next)
+            }));
+
+    let address = serve(app).await;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let client = reqwest::Client::new();
+
+    let send = |channel: PaymentChannel| {
+        let canonical = CanonicalRequest::new(
+            "GET",
+            "/",
+            "",
+            vec![("idempotency-key".to_string(), "retry-1".to_string())],
+            timestamp,
+        );
+        let request = client.get(format!("http://{}/", address));
+        paid_at(
+            request,
+            &sender,
+            &channel,
+            &canonical.encode(b""),
+            timestamp,
+        )
+        .header("X-Signed-Headers", "idempotency-key")
+        .header("Idempotency-Key", "retry-1")
+        .send()
+    };
+
+    let first = send(channel.clone()).await.unwrap();
+    assert_eq!(first.status(), 200);
+    let payment = first.headers()["X-Payment"].clone();
+    assert_eq!(first.text().await.unwrap(), "1");
+
+    // The response got lost, the client retries the same request
+    let retry = send(channel.clone()).await.unwrap();
+    assert_eq!(retry.status(), 200);
+    assert_eq!(retry.headers()["X-Payment"], payment);
+    assert_eq!(retry.text().await.unwrap(), "1");
+
+    assert_eq!(served.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        state.get_channel(channel.channel_id).await.unwrap().balance,
+        U256::from(DEPOSIT - PRICE)
+    );
+
+    // Same key for another request
+    let next = PaymentChannel {
+        nonce: U256::from(1),
+        balance: U256::from(DEPOSIT - PRICE),
+        ..channel
+    };
+    let reused = send(next).await.unwrap();
+    assert_eq!(reused.status(), 422);
+}
+
+#[tokio::test]
+async fn retries_without_kept_responses_are_not_served_again() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    // Responses aren't kept by default
+    let state = ChannelState::new(rpc.url()).with_idempotency(Duration::from_secs(60), false);
+
+    let served = Arc::new(AtomicUsize::new(0));
+    let handler_served = served.clone();
+    let handler_state = state.clone();
+    let app =
+        Router::new()
+            .route(
+                "/",
+                get(move || async move {
+                    (handler_served.fetch_add(1, Ordering::SeqCst) + 1).to_string()
+                }),
+            )
+            .layer(axum::middleware::from_fn(move |req, next| {
+                let state = handler_state.clone();
+                auth_middleware(state, U256::from(PRICE), req, next)
+            }));
+
+    let address = serve(app).await;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let client = reqwest::Client::new();
+
+    let send = || {
+        let canonical = CanonicalRequest::new(
+            "GET",
+            "/",
+            "",
+            vec![("idempotency-key".to_string(), "retry-1".to_string())],
+            timestamp,
+        );
+        let request = client.get(format!("http://{}/", address));
+        paid_at(
+            request,
+            &sender,
+            &channel,
+            &canonical.encode(b""),
+            timestamp,
+        )
+        .header("X-Signed-Headers", "idempotency-key")
+        .header("Idempotency-Key", "retry-1")
+        .send()
+    };
+
+    let first = send().await.unwrap();
+    assert_eq!(first.status(), 200);
+    let payment = first.headers()["X-Payment"].clone();
+
+    // Replaying the voucher doesn't get the handler to run again for free
+    for _ in 0..3 {
+        let retry = send().await.unwrap();
+        assert_eq!(retry.status(), 409);
+        assert_eq!(retry.headers()["X-Payment"], payment);
+    }
+
+    assert_eq!(served.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        state.get_channel(channel.channel_id).await.unwrap().balance,
+        U256::from(DEPOSIT - PRICE)
+    );
+}
+
+#[tokio::test]
+async fn dropped_requests_release_their_idempotency_key() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let state = ChannelState::new(rpc.url()).with_idempotency(Duration::from_secs(60), true);
+
+    // The handler never answers, the client gives up while it runs
+    let started = Arc::new(tokio::sync::Notify::new());
+    let handler_started = started.clone();
+    let handler_state = state.clone();
+    let app = Router::new()
+        .route(
+            "/",
+            get(move || async move {
+                handler_started.notify_one();
+                std::future::pending::<String>().await
+            }),
+        )
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = handler_state.clone();
+            auth_middleware(state, U256::from(PRICE), req, next)
+        }));
+
+    let address = serve(app).await;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let send = |client: reqwest::Client| {
+        let canonical = CanonicalRequest::new(
+            "GET",
+            "/",
+            "",
+            vec![("idempotency-key".to_string(), "retry-1".to_string())],
+            timestamp,
+        );
+        let request = client.get(format!("http://{}/", address));
+        paid_at(
+            request,
+            &sender,
+            &channel,
+            &canonical.encode(b""),
+            timestamp,
+        )
+        .header("X-Signed-Headers", "idempotency-key")
+        .header("Idempotency-Key", "retry-1")
+        .send()
+    };
+
+    // Dropping the client closes the connection, which drops the request future mid-handler
+    let first = tokio::spawn(send(reqwest::Client::new()));
+    started.notified().await;
+    first.abort();
+
+    // The charge is kept, the retries learn about it instead of waiting for the key to expire
+    let mut retry = send(reqwest::Client::new()).await.unwrap();
+    for _ in 0..50 {
+        if retry.headers().contains_key("X-Payment") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        retry = send(reqwest::Client::new()).await.unwrap();
+    }
+    assert_eq!(retry.status(), 409);
+    assert!(retry.headers().contains_key("X-Payment"));
+    assert_eq!(
+        state.get_channel(channel.channel_id).await.unwrap().balance,
+        U256::from(DEPOSIT - PRICE)
+    );
+}
+
+#[tokio::test]
+async fn resyncs_clients_that_lost_their_state() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let server = PrivateKeySigner::random();
+    let state = ChannelState::new(rpc.url()).with_signer(server.clone());
+
+    let request = sign(&sender, &channel, b"");
+    let voucher_signature = request.signature;
+    let served = verify_and_update_channel(&state, request).await.unwrap();
+
+    let app_state = state.clone();
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = app_state.clone();
+            auth_middleware(state, U256::from(PRICE), req, next)
+        }));
+
+    let url = format!("http://{}{}", serve(app).await, RESYNC_PATH);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let client = reqwest::Client::new();
+    let resync = |signer: &PrivateKeySigner| {
+        let proof = signer
+            .sign_message_sync(&create_resync_message(channel.channel_id, timestamp))
+            .unwrap();
+        client
+            .get(&url)
+            .header("X-Channel-Id", channel.channel_id.to_string())
+            .header("X-Timestamp", timestamp.to_string())
+            .header("X-Signature", hex::encode_prefixed(proof.as_bytes()))
+            .send()
+    };
+
+    // Only the sender can resync
+    let response = resync(&PrivateKeySigner::random()).await.unwrap();
+    assert_eq!(response.status(), 401);
+
+    let response = resync(&sender).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let snapshot: ChannelSnapshot = response.json().await.unwrap();
+
+    assert!(snapshot.verify(server.address()));
+    assert!(!snapshot.verify(sender.address()));
+    assert_eq!(snapshot.channel.nonce, served.nonce);
+    assert_eq!(snapshot.channel.balance, served.balance);
+    assert_eq!(snapshot.voucher.unwrap().signature, voucher_signature);
+}
+
+#[tokio::test]
+async fn issues_receipts_for_paid_requests() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let server = PrivateKeySigner::random();
+    let state = ChannelState::new(rpc.url()).with_signer(server.clone());
+
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = state.clone();
+            auth_middleware(state, U256::from(PRICE), req, next)
+        }));
+
+    let url = format!("http://{}/", serve(app).await);
+
+    let response = paid(reqwest::Client::new().get(&url), &sender, &channel, b"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let receipt: Receipt =
+        serde_json::from_str(response.headers()["X-Receipt"].to_str().unwrap()).unwrap();
+    let body = response.bytes().await.unwrap();
+
+    assert_eq!(receipt.channel_id, channel.channel_id);
+    assert_eq!(receipt.nonce, channel.nonce);
+    assert_eq!(receipt.amount, U256::from(PRICE));
+    assert_eq!(receipt.balance, U256::from(DEPOSIT - PRICE));
+    assert!(receipt.covers_response());
+    assert!(receipt.verify_exchange(server.address(), b"", &body));
+
+    // Not what was delivered, or not from this server
+    assert!(!receipt.verify_exchange(server.address(), b"", b"Goodbye"));
+    assert!(!receipt.verify(sender.address()));
+    let forged = Receipt {
+        amount: U256::from(2 * PRICE),
+        ..receipt
+    };
+    assert!(!forged.verify(server.address()));
+}
+
+#[tokio::test]
+async fn handlers_see_the_payer() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let state = ChannelState::new(rpc.url());
+
+    let app = Router::new()
+        .route(
+            "/",
+            get(|payment: PaymentContext| async move { Json(payment) }),
+        )
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = state.clone();
+            auth_middleware(state, U256::from(PRICE), req, next)
+        }))
+        // Outside the middleware, free
+        .route(
+            "/free",
+            get(|payment: Option<PaymentContext>| async move { payment.is_none().to_string() }),
+        );
+
+    let address = serve(app).await;
+
+    let client = reqwest::Client::new();
+    let response = paid(
+        client.get(format!("http://{}/", address)),
+        &sender,
+        &channel,
+        b"",
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let payment: PaymentContext = response.json().await.unwrap();
+    assert_eq!(
+        payment,
+        PaymentContext {
+            sender: sender.address(),
+            channel_id: channel.channel_id,
+            nonce: channel.nonce,
+            amount: U256::from(PRICE),
+            balance: U256::from(DEPOSIT - PRICE),
+        }
+    );
+
+    let response = client
+        .get(format!("http://{}/free", address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "true");
+}
+
+#[tokio::test]
+async fn paid_handlers_charge_on_their_own() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let state = ChannelState::new(rpc.url());
+
+    // Free and paid handlers in the same router, no middleware
+    let app = Router::new()
+        .route("/free", get(|| async { "free" }))
+        .route(
+            "/paid",
+            post(|paid: Paid<PerRequest<PRICE>>| async move {
+                let text = String::from_utf8_lossy(&paid.body).to_string();
+                (paid.headers(), text)
+            }),
+        )
+        .with_state(state);
+
+    let address = serve(app).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("http://{}/free", address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Unpaid
+    let response = client
+        .post(format!("http://{}/paid", address))
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let pay = || {
+        let request = client.post(format!("http://{}/paid", address));
+        paid(request, &sender, &channel, b"hello").body("hello")
+    };
+    let response = pay().send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let updated: PaymentChannel =
+        serde_json::from_str(response.headers()["X-Payment"].to_str().unwrap()).unwrap();
+    assert_eq!(updated.balance, U256::from(DEPOSIT - PRICE));
+    assert_eq!(response.text().await.unwrap(), "hello");
+
+    // Replaying the voucher is refused
+    let response = pay().send().await.unwrap();
+    assert!(!response.status().is_success());
+}
+
+#[tokio::test]
+async fn free_routes_and_internal_senders_skip_payment() {
+    let Setup { rpc, .. } = setup().await;
+    let internal = PrivateKeySigner::random();
+    let state = ChannelState::new(rpc.url()).with_access(
+        AccessPolicy::new()
+            .with_free_path("/health")
+            .with_free_path("/docs/*")
+            .with_allowed_sender(internal.address()),
+    );
+
+    let app = Router::new()
+        .route("/", get(|| async { "paid" }))
+        .route("/health", get(|| async { "ok" }))
+        .route("/docs/api", get(|| async { "docs" }))
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = state.clone();
+            auth_middleware(state, U256::from(PRICE), req, next)
+        }));
+
+    let address = serve(app).await;
+
+    let client = reqwest::Client::new();
+    for (path, status) in [("/health", 200), ("/docs/api", 200), ("/", 400)] {
+        let response = client
+            .get(format!("http://{}{}", address, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", path);
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let internal_request = |signer: &PrivateKeySigner, path: &str| {
+        let token = sign_internal_token(signer, &axum::http::Method::GET, path, timestamp).unwrap();
+        client
+            .get(format!("http://{}/", address))
+            .header(INTERNAL_HEADER, hex::encode_prefixed(token.as_bytes()))
+            .header("X-Timestamp", timestamp.to_string())
+    };
+
+    let response = internal_request(&internal, "/").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "paid");
+
+    // Signed for another endpoint
+    let response = internal_request(&internal, "/other").send().await.unwrap();
+    assert_eq!(response.status(), 403);
+
+    // Not on the allowlist
+    let outsider = PrivateKeySigner::random();
+    let response = internal_request(&outsider, "/").send().await.unwrap();
+    assert_eq!(response.status(), 403);
+}
+
+#[tokio::test]
+async fn browser_mode_answers_preflights_and_exposes_headers() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+    let state = ChannelState::new(rpc.url()).with_browser_mode(true);
+
+    let app = Router::new()
+        .route("/", get(|| async { "paid" }))
+        .layer(axum::middleware::from_fn(move |req, next| {
+            let state = state.clone();
+            auth_middleware(state, U256::from(PRICE), req, next)
+        }));
+
+    let address = serve(app).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .request(reqwest::Method::OPTIONS, format!("http://{}/", address))
+        .header("Origin", "https://dapp.example")
+        .header("Access-Control-Request-Method", "GET")
+        .header("Access-Control-Request-Headers", "x-payment, x-custom")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    let allowed = response.headers()["access-control-allow-headers"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(allowed.contains("X-Signature") && allowed.contains("x-custom"));
+    assert_eq!(response.headers()["access-control-allow-methods"], "GET");
+
+    // Errors are readable by the browser too
+    let response = client
+        .get(format!("http://{}/", address))
+        .header("Origin", "https://dapp.example")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(response.headers()["access-control-allow-origin"], "*");
+
+    let request = client.get(format!("http://{}/", address));
+    let response = paid(request, &sender, &channel, b"")
+        .header("Origin", "https://dapp.example")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let exposed = response.headers()["access-control-expose-headers"]
+        .to_str()
+        .unwrap();
+    assert!(exposed.contains("X-Payment") && exposed.contains("X-Timestamp"));
+    assert!(response.headers().contains_key("X-Payment"));
+}
+
+#[tokio::test]
+async fn gateway_forwards_paid_requests_upstream() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+
+    // Echoes what the gateway forwarded
+    let upstream = Router::new().fallback(|request: axum::extract::Request| async move {
+        let payer = request
+            .headers()
+            .get(SENDER_HEADER)
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let uri = request.uri().to_string();
+        // None of the headers meant for the gateway
+        let leaked: Vec<String> = request
+            .headers()
+            .keys()
+            .map(|name| name.to_string())
+            .filter(|name| {
+                !name.starts_with("x-pipegate-")
+                    && (name.starts_with("x-") || name == "idempotency-key")
+            })
+            .collect();
+        let body = axum::body::to_bytes(request.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        format!(
+            "{} {} {}{}",
+            payer,
+            uri,
+            String::from_utf8_lossy(&body),
+            leaked.join(",")
+        )
+    });
+    let upstream_address = serve(upstream).await;
+
+    let mut config = GatewayConfig::from_file("gateway.example.toml").unwrap();
+    config.upstream = format!("http://{}/api", upstream_address).parse().unwrap();
+    config.pipegate.network.rpc_url = rpc.url();
+    config.pipegate.pricing.default = U256::from(PRICE);
+
+    let pipegate = config.pipegate.build().unwrap();
+    let address = serve(gateway_router(&pipegate, config.upstream)).await;
+
+    let client = reqwest::Client::new();
+
+    // Free, and the client can't claim to be someone
+    let response = client
+        .get(format!("http://{}/health", address))
+        .header(SENDER_HEADER, sender.address().to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), " /api/health ");
+
+    let response = client
+        .post(format!("http://{}/items?page=2", address))
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let request = client.post(format!("http://{}/items?page=2", address));
+    let response = paid(request, &sender, &channel, b"hello")
+        .header("Idempotency-Key", "items-1")
+        .header("Connection", "keep-alive, X-Hop")
+        .header("X-Hop", "1")
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().contains_key("X-Payment"));
+    assert_eq!(
+        response.text().await.unwrap(),
+        format!("{} /api/items?page=2 hello", sender.address())
+    );
+}
+
+#[tokio::test]
+async fn gateway_records_charged_upstream_failures() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+
+    // Nothing listens there anymore
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap();
+    drop(listener);
+
+    let mut config = GatewayConfig::from_file("gateway.example.toml").unwrap();
+    config.upstream = format!("http://{}/", upstream_address).parse().unwrap();
+    config.pipegate.network.rpc_url = rpc.url();
+    config.pipegate.pricing.default = U256::from(PRICE);
+    let audit_path =
+        std::env::temp_dir().join(format!("pipegate-upstream-{}.jsonl", channel.sender));
+    let _ = std::fs::remove_file(&audit_path);
+    config.pipegate.storage.audit_log = Some(audit_path);
+
+    let pipegate = config.pipegate.build().unwrap();
+    let address = serve(gateway_router(&pipegate, config.upstream)).await;
+
+    let request = reqwest::Client::new().get(format!("http://{}/items", address));
+    let response = paid(request, &sender, &channel, b"").send().await.unwrap();
+    assert_eq!(response.status(), 502);
+
+    // Charged all the same, and recorded as such
+    let entries = pipegate.state().audit_log().unwrap().entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].amount, U256::from(PRICE));
+    let change = entries[1].change.as_deref().unwrap();
+    assert!(change.starts_with("upstream failed for GET /items"));
+    assert!(change.contains(&format!("channel {}", channel.channel_id)));
+}
+
+#[tokio::test]
+async fn gateway_streams_responses_with_receipts() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+
+    // Sends the first chunk, and the rest only once the client got it
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap();
+    let received = Arc::new(tokio::sync::Notify::new());
+    let upstream_received = received.clone();
+    tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0; 4096];
+        let _ = stream.read(&mut request).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n")
+            .await
+            .unwrap();
+        upstream_received.notified().await;
+        stream
+            .write_all(b"7\r\n, World\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+    });
+
+    let server = PrivateKeySigner::random();
+    let key_env = format!("PIPEGATE_STREAM_KEY_{}", server.address());
+    std::env::set_var(&key_env, hex::encode(server.to_bytes()));
+
+    let mut config = GatewayConfig::from_file("gateway.example.toml").unwrap();
+    config.upstream = format!("http://{}/", upstream_address).parse().unwrap();
+    config.pipegate.network.rpc_url = rpc.url();
+    config.pipegate.pricing.default = U256::from(PRICE);
+    config.pipegate.server.signer_key_env = Some(key_env);
+
+    let pipegate = config.pipegate.build().unwrap();
+    let address = serve(gateway_router(&pipegate, config.upstream)).await;
+
+    let request = reqwest::Client::new().get(format!("http://{}/", address));
+    let mut response = tokio::time::timeout(
+        Duration::from_secs(5),
+        paid(request, &sender, &channel, b"").send(),
+    )
+    .await
+    .expect("response buffered")
+    .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Signed for the request and the charge, without the response that wasn't read yet
+    let receipt: Receipt =
+        serde_json::from_str(response.headers()["X-Receipt"].to_str().unwrap()).unwrap();
+    assert!(!receipt.covers_response());
+    assert!(receipt.verify(server.address()));
+    assert_eq!(receipt.amount, U256::from(PRICE));
+
+    let first = response.chunk().await.unwrap().unwrap();
+    assert_eq!(first.as_ref(), b"Hello");
+    received.notify_one();
+    let mut rest = Vec::new();
+    while let Some(chunk) = response.chunk().await.unwrap() {
+        rest.extend_from_slice(&chunk);
+    }
+    assert_eq!(rest, b", World");
+}
+
+#[tokio::test]
+async fn reloads_prices_without_restarting() {
+    let Setup {
+        rpc,
+        sender,
+        channel,
+        ..
+    } = setup().await;
+
+    let path = std::env::temp_dir().join(format!("pipegate-reload-{}.toml", channel.sender));
+    let config = |price: u64| {
+        format!(
+            "[network]\nrpc_url = \"{}\"\n[pricing]\ndefault = \"{}\"\n",
+            rpc.url(),
+            price
+        )
+    };
+    std::fs::write(&path, config(PRICE)).unwrap();
+
+    let pipegate = PipegateConfig::from_file(&path).unwrap().build().unwrap();
+    let app = Router::new()
+        .route("/", get(|| async { "paid" }))
+        .layer(pipegate.layer());
+
+    let address = serve(app).await;
+
+    let client = reqwest::Client::new();
+    let pay = |channel: PaymentChannel| {
+        paid(
+            client.get(format!("http://{}/", address)),
+            &sender,
+            &channel,
+            b"",
+        )
+        .send()
+    };
+    let updated = |response: &reqwest::Response| -> PaymentChannel {
+        serde_json::from_str(response.headers()["X-Payment"].to_str().unwrap()).unwrap()
+    };
+
+    let response = pay(channel.clone()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let channel = updated(&response);
+    assert_eq!(channel.balance, U256::from(DEPOSIT - PRICE));
+
+    // Twice the price from now on
+    std::fs::write(&path, config(2 * PRICE)).unwrap();
+    let settings = pipegate.reload(&path, "test").unwrap();
+    assert_eq!(
+        settings
+            .pricing
+            .as_ref()
+            .unwrap()
+            .price(&axum::http::Method::GET, "/"),
+        U256::from(2 * PRICE)
+    );
+
+    let response = pay(PaymentChannel {
+        nonce: channel.nonce + U256::from(1),
+        ..channel
+    })
+    .await
+    .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(updated(&response).balance, U256::from(DEPOSIT - 3 * PRICE));
+
+    // An invalid file leaves the settings as they were
+    std::fs::write(&path, config(0)).unwrap();
+    assert!(pipegate.reload(&path, "test").is_err());
+    assert_eq!(
+        pipegate
+            .state()
+            .settings()
+            .pricing
+            .as_ref()
+            .unwrap()
+            .price(&axum::http::Method::GET, "/"),
+        U256::from(2 * PRICE)
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn admin_api_inspects_and_manages_channels() {
+    let Setup {
+        rpc,
+        sender,
+        recipient,
+        channel,
+    } = setup().await;
+    let state = ChannelState::new(rpc.url())
+        .with_signer(recipient.clone())
+        .with_audit_log(AuditLog::new());
+
+    let request = sign(&sender, &channel, b"");
+    let channel = verify_and_update_channel(&state, request).await.unwrap();
+
+    let app = Router::new().nest("/admin", Admin::new(state.clone(), "secret").router());
+    let address = serve(app).await;
+
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}/admin{}", address, path);
+
+    // Nothing without the token
+    let response = client.get(url("/channels")).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+    let response = client
+        .get(url("/channels"))
+        .bearer_auth("wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+
+    let channels: Vec<ChannelSummary> = client
+        .get(url("/channels"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].channel_id, channel.channel_id);
+    assert_eq!(channels[0].accrued, U256::from(PRICE));
+    assert_eq!(channels[0].balance, U256::from(DEPOSIT - PRICE));
+    assert_eq!(channels[0].status, ChannelStatus::Active);
+    assert!(channels[0].settleable);
+
+    let history: SenderHistory = client
+        .get(url(&format!("/senders/{}", sender.address())))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(history.channels.len(), 1);
+    assert_eq!(history.charges.len(), 1);
+    assert!(!history.blocked);
+
+    // Blocked senders are refused until unblocked
+    let response = client
+        .post(url(&format!("/senders/{}/block", sender.address())))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    let next = PaymentChannel {
+        nonce: channel.nonce + U256::from(1),
+        ..channel.clone()
+    };
+    let result = verify_and_update_channel(&state, sign(&sender, &next, b"")).await;
+    assert!(matches!(result, Err(AuthError::SenderNotAllowed)));
+
+    client
+        .delete(url(&format!("/senders/{}/block", sender.address())))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    verify_and_update_channel(&state, sign(&sender, &next, b""))
+        .await
+        .unwrap();
+
+    // Waive one of the requests
+    let adjusted: ChannelSummary = client
+        .post(url(&format!("/channels/{}/adjust", channel.channel_id)))
+        .bearer_auth("secret")
+        .json(&serde_json::json!({ "served": PRICE.to_string() }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(adjusted.accrued, U256::from(PRICE));
+    assert_eq!(adjusted.balance, U256::from(DEPOSIT - PRICE));
+
+    // Only the middleware puts a channel up for validation
+    let response = client
+        .post(url(&format!("/channels/{}/adjust", channel.channel_id)))
+        .bearer_auth("secret")
+        .json(&serde_json::json!({ "status": "pending_validation" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 422);
+
+    // Settled with the server signer and the best voucher
+    let settlement: Settlement = client
+        .post(url(&format!("/channels/{}/settle", channel.channel_id)))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(settlement.channel_id, channel.channel_id);
+
+    let closes = rpc.closes();
+    assert_eq!(closes.len(), 1);
+    assert_eq!(closes[0].from, recipient.address());
+    assert_eq!(closes[0].balance, next.balance);
+    assert_eq!(
+        state.get_status(channel.channel_id).await,
+        Some(ChannelStatus::Closed)
+    );
+
+    // Blocks and adjustments are in the audit log
+    let changes: Vec<_> = state
+        .audit_log()
+        .unwrap()
+        .entries()
+        .into_iter()
+        .filter_map(|entry| entry.change)
+        .collect();
+    assert_eq!(changes.len(), 3);
+    assert!(changes.iter().all(|change| change.contains("(admin API)")));
+}
+
+#[tokio::test]
+async fn cli_registers_inspects_and_settles() {
+    let Setup {
+        rpc,
+        sender,
+        recipient,
+        channel,
+    } = setup().await;
+    let factory = Address::repeat_byte(9);
+    rpc.register_channel(factory, channel.channel_id, channel.address);
+
+    let key_env = format!("PIPEGATE_TEST_KEY_{}", recipient.address());
+    std::env::set_var(&key_env, recipient.to_bytes().to_string());
+
+    let cli = Cli::try_parse_from([
+        "pipegate",
+        "--rpc-url",
+        rpc.url().as_str(),
+        "--factory",
+        &factory.to_string(),
+        "--key-env",
+        &key_env,
+        "doctor",
+    ])
+    .unwrap();
+    let context = Context {
+        recipients: vec![recipient.address()],
+        default_price: Some(U256::from(PRICE)),
+        ..cli.options.context().unwrap()
+    };
+
+    // Nothing registered, nor any gas yet
+    let checks = doctor(&context, Some(84532)).await;
+    let status = |checks: &[pipegate::cli::Check], name: &str| {
+        checks
+            .iter()
+            .find(|check| check.name == name)
+            .map(|check| check.status)
+    };
+    assert_eq!(status(&checks, "rpc"), Some(CheckStatus::Ok));
+    assert_eq!(status(&checks, "key"), Some(CheckStatus::Ok));
+    assert_eq!(status(&checks, "gas"), Some(CheckStatus::Warning));
+    assert_eq!(status(&checks, "factory"), Some(CheckStatus::Warning));
+
+    rpc.set_balance(recipient.address(), U256::from(10u64.pow(18)));
+    let (price, _) = register(&context, None).await.unwrap();
+    assert_eq!(price, U256::from(PRICE));
+    assert_eq!(rpc.pricing(factory, recipient.address()), U256::from(PRICE));
+
+    let checks = doctor(&context, Some(84532)).await;
+    assert!(checks.iter().all(|check| check.status == CheckStatus::Ok));
+    let checks = doctor(&context, Some(1)).await;
+    assert_eq!(status(&checks, "rpc"), Some(CheckStatus::Failed));
+
+    let info = inspect(&context, None, Some(channel.channel_id))
+        .await
+        .unwrap();
+    assert_eq!(info.address, channel.address);
+    assert_eq!(info.balance, U256::from(DEPOSIT));
+
+    // Settled with the voucher the server kept, as exported by the admin API
+    let state = ChannelState::new(rpc.url());
+    verify_and_update_channel(&state, sign(&sender, &channel, b""))
+        .await
+        .unwrap();
+    let voucher = state
+        .get_record(channel.channel_id)
+        .await
+        .unwrap()
+        .voucher
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("pipegate-voucher-{}.json", channel.sender));
+    std::fs::write(&path, serde_json::to_string(&voucher).unwrap()).unwrap();
+    let voucher = read_voucher(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    settle(&context, &voucher).await.unwrap();
+    let closes = rpc.closes();
+    assert_eq!(closes.len(), 1);
+    assert_eq!(closes[0].from, recipient.address());
+    assert_eq!(closes[0].balance, channel.balance);
+
+    // Already drained, nothing is sent
+    let result = settle(&context, &voucher).await;
+    assert!(matches!(result, Err(CliError::Refused(_))));
+    assert_eq!(rpc.closes().len(), 1);
+
+    // A bad key is reported, not a panic
+    std::env::set_var(&key_env, "not a key");
+    let result = register(&context, None).await;
+    assert!(matches!(result, Err(CliError::Config(_))));
+    std::env::remove_var(&key_env);
+}
+
+#[tokio::test]
+async fn settles_channel_over_rpc() {
+    let Setup {
+        rpc,
+        sender,
+        recipient,
+        channel,
+    } = setup().await;
+    let state = ChannelState::new(rpc.url());
+
+    let request = sign(&sender, &channel, b"{}");
+    let signature = request.signature;
+    verify_and_update_channel(&state, request).await.unwrap();
+
+    // Not signed by the sender of the channel, the contract reverts
+    let forged = sign(&recipient, &channel, b"{}").signature;
+    let result = state
+        .settle_channel(&recipient, &channel, &forged, Bytes::from_static(b"{}"))
+        .await;
+    assert!(matches!(result, Err(AuthError::ContractError(_))));
+    assert!(rpc.closes().is_empty());
+
+    state
+        .settle_channel(&recipient, &channel, &signature, Bytes::from_static(b"{}"))
+        .await
+        .unwrap();
+
+    let closes = rpc.closes();
+    assert_eq!(closes.len(), 1);
+    assert_eq!(closes[0].from, recipient.address());
+    assert_eq!(closes[0].channel, channel.address);
+    assert_eq!(closes[0].balance, channel.balance);
+    assert_eq!(closes[0].raw_body, Bytes::from_static(b"{}"));
+    assert_eq!(closes[0].signature, Bytes::from(signature.as_bytes()));
+
+    assert_eq!(
+        state.get_status(channel.channel_id).await,
+        Some(ChannelStatus::Closed)
+    );
+    assert_eq!(rpc.channel(channel.address).unwrap().balance, U256::ZERO);
+}
+
+#[tokio::test]
+async fn settles_body_hash_voucher_without_the_body() {
+    let Setup {
+        rpc,
+        sender,
+        recipient,
+        channel,
+    } = setup().await;
+    let state = ChannelState::new(rpc.url());
+
+    // A large upload, only its hash is signed
+    let body = vec![7u8; 1 << 20];
+    let message =
+        create_body_hash_message(channel.channel_id, channel.balance, channel.nonce, &body);
+    let signature = sender.sign_message_sync(&message).unwrap();
+    let signature = Signature::try_from(signature.as_bytes().as_slice()).unwrap();
+
+    let request = SignedRequest {
+        scheme: MessageScheme::BodyHash,
+        message,
+        signature,
+        payment_channel: channel.clone(),
+        payment_amount: U256::from(PRICE),
+        body_bytes: body.clone(),
+        request: None,
+        route: None,
+    };
+    verify_and_update_channel(&state, request).await.unwrap();
+
+    let raw_body = state
+        .settlement_body(MessageScheme::BodyHash, &channel, &body)
+        .await
+        .unwrap();
+    state
+        .settle_channel(&recipient, &channel, &signature, raw_body)
+        .await
+        .unwrap();
+
+    // The calldata only carries the hash, which is what the contract verifies the signature over
+    let closes = rpc.closes();
+    assert_eq!(closes[0].raw_body.len(), 32);
+    let signed = create_message(
+        channel.channel_id,
+        closes[0].balance,
+        closes[0].nonce,
+        &closes[0].raw_body,
+    );
+    assert_eq!(
+        signature.recover_address_from_msg(signed).unwrap(),
+        sender.address()
+    );
+}